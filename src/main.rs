@@ -1,30 +1,103 @@
 use anyhow::Result;
+use clap::Parser as _;
 use colored::*;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
 use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 use serde::{Deserialize, Serialize};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use textwrap::{wrap, Options};
-use tokio;
 use pulldown_cmark::{Parser, Event, Tag, CodeBlockKind, Alignment};
 use syntect::easy::HighlightLines;
-use syntect::highlighting::ThemeSet;
+use syntect::highlighting::{Theme, ThemeSet};
 use syntect::parsing::SyntaxSet;
 use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
 
+#[cfg(feature = "tui")]
+mod tui;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct ChatMessage {
     role: String,
     content: String,
+    /// When the message was sent or received. Kept in saved conversations
+    /// but never sent to the API; missing in older ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<chrono::DateTime<chrono::Local>>,
+}
+
+impl ChatMessage {
+    /// A message stamped with the current time.
+    fn now(role: &str, content: String) -> Self {
+        Self {
+            role: role.to_string(),
+            content,
+            created_at: Some(chrono::Local::now()),
+        }
+    }
+}
+
+/// Writes messages with just their role and content, the fields the API
+/// knows about.
+fn serialize_api_messages<S: serde::Serializer>(
+    messages: &[ChatMessage],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct ApiMessage<'a> {
+        role: &'a str,
+        content: &'a str,
+    }
+    serializer.collect_seq(messages.iter().map(|msg| ApiMessage {
+        role: &msg.role,
+        content: &msg.content,
+    }))
+}
+
+/// Sampling parameters for a chat request. Unset fields are left out of
+/// the request so the API defaults apply.
+#[derive(Debug, Clone, Default, Serialize)]
+struct Params {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+impl Params {
+    /// Returns these parameters with any field set in `overrides` replaced.
+    fn merged_with(&self, overrides: &Params) -> Params {
+        Params {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct ChatRequest {
     model: String,
+    #[serde(serialize_with = "serialize_api_messages")]
     messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    params: Params,
+    /// Bias added to the likelihood of each token id, -100 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logit_bias: Option<HashMap<String, f32>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,20 +105,655 @@ struct Choice {
     message: ChatMessage,
 }
 
+/// Token counts the API reports with a completion.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
 #[derive(Debug, Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+/// Request body for the older text-completion endpoint, which takes a
+/// flat prompt rather than a list of messages.
+#[derive(Debug, Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    #[serde(flatten)]
+    params: &'a Params,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logit_bias: Option<&'a HashMap<String, f32>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+}
+
+/// One event of a streamed reply.
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    /// Missing from keep-alive and usage-only events on some servers
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Sent by some servers with the last chunk
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    /// Left out of the closing chunk by some servers
+    #[serde(default)]
+    delta: StreamDelta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
+
+/// Marks the end of a streamed reply, as the last `data:` payload.
+const STREAM_DONE_MARKER: &str = "[DONE]";
+
+/// Splits a server-sent events body into the payloads of its `data:` lines.
+/// Bytes are buffered until a full line arrives, since network chunks don't
+/// line up with events.
+///
+/// OpenAI-compatible servers don't all frame events the same way, so this
+/// is lenient: `event:`, `id:` and `retry:` fields, `:` comments and blank
+/// keep-alive lines are skipped, and a line that is bare JSON or a bare
+/// done marker such as `[DONE]` or one of `done_markers`, without the
+/// `data:` prefix, is taken as a payload too.
+#[derive(Debug, Default)]
+struct SseDecoder {
+    buffer: Vec<u8>,
+    /// Custom done markers, accepted as bare lines
+    done_markers: Vec<String>,
+}
+
+impl SseDecoder {
+    fn new(done_markers: &[String]) -> Self {
+        Self {
+            buffer: Vec::new(),
+            done_markers: done_markers.to_vec(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut payloads = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if let Some(data) = line.strip_prefix("data:") {
+                payloads.push(data.trim_start().to_string());
+            } else if line.starts_with(['{', '[']) || self.done_markers.iter().any(|marker| marker == line) {
+                payloads.push(line.to_string());
+            }
+        }
+        payloads
+    }
+}
+
+/// Gzips a request body if `compress` is on and the body is over
+/// `COMPRESS_ABOVE_BYTES`, returning the body to send and its
+/// `Content-Encoding`, if any.
+fn encode_body(body: Vec<u8>, compress: bool) -> Result<(Vec<u8>, Option<&'static str>)> {
+    if !compress || body.len() <= COMPRESS_ABOVE_BYTES {
+        return Ok((body, None));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&body)?;
+    Ok((encoder.finish()?, Some("gzip")))
+}
+
+/// Converts `\r\n` and lone `\r` line endings to `\n`, so pasted or
+/// Windows-style text doesn't leak carriage returns into messages or output.
+fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Line endings used when writing output. Text is always handled with `\n`
+/// internally and only converted at the final write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+    /// `\r\n` on Windows, `\n` elsewhere
+    Native,
+}
+
+impl LineEnding {
+    fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let crlf = match self {
+            LineEnding::Lf => false,
+            LineEnding::Crlf => true,
+            LineEnding::Native => cfg!(windows),
+        };
+        if crlf {
+            Cow::Owned(text.replace('\n', "\r\n"))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+/// How the API key is sent with each request. Gateways in front of the API
+/// don't all accept `Authorization: Bearer`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AuthScheme {
+    /// `Authorization: Bearer <key>`
+    #[default]
+    Bearer,
+    /// The bare key in a header of this name, e.g. `x-api-key`
+    Header(String),
+    /// No credentials, for gateways that add their own
+    None,
+}
+
+const DEFAULT_BASE_URL: &str = "https://api.mistral.ai";
+const DEFAULT_MODEL: &str = "mistral-small";
+/// Path of the text-completion endpoint, relative to the API root.
+const DEFAULT_COMPLETIONS_PATH: &str = "/v1/completions";
+
+/// Request bodies up to this size are sent uncompressed even when
+/// compression is on, as gzip wouldn't save anything worth the effort.
+const COMPRESS_ABOVE_BYTES: usize = 1024;
+
+/// Largest logit bias allowed either way.
+const MAX_LOGIT_BIAS: f32 = 100.0;
+
+/// Tokens `estimate_tokens` allows for each message's role and framing.
+const TOKENS_PER_MESSAGE: usize = 4;
+/// Largest response body accepted by default. Far more than any real reply,
+/// but stops a misbehaving endpoint from exhausting memory.
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+
+/// A failed API call, kept structured so the UI can explain what went wrong.
+#[derive(Debug)]
+enum ApiError {
+    /// The server answered with a non-success status.
+    Status {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    /// The request could not be built, usually because of a malformed base URL.
+    InvalidUrl(String),
+    /// No response was received (DNS, connection refused, TLS, timeout).
+    Network(reqwest::Error),
+    /// The response body was larger than the configured limit.
+    ResponseTooLarge { limit: usize },
+}
+
+impl ApiError {
+    /// Extracts a readable message from an error response body. Mistral and
+    /// OpenAI-compatible servers use a few different shapes for this.
+    fn from_response(status: reqwest::StatusCode, body: &str) -> Self {
+        let json: Option<serde_json::Value> = serde_json::from_str(body).ok();
+        let message = json
+            .as_ref()
+            .and_then(|json| {
+                json.get("message")
+                    .or_else(|| json.get("error").and_then(|error| error.get("message")))
+                    .or_else(|| json.get("error"))
+                    .or_else(|| json.get("detail"))
+            })
+            .map(|value| match value {
+                serde_json::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            })
+            .unwrap_or_else(|| body.trim().to_string());
+        ApiError::Status { status, message }
+    }
+
+    /// Whether the server turned the request down for being longer than
+    /// the model's context window. Servers word this differently, so the
+    /// message is matched against the usual phrasings.
+    fn is_context_length(&self) -> bool {
+        let ApiError::Status { status, message } = self else {
+            return false;
+        };
+        if !matches!(status.as_u16(), 400 | 413 | 422) {
+            return false;
+        }
+        let message = message.to_lowercase();
+        [
+            "context length",
+            "context_length",
+            "context window",
+            "maximum context",
+            "too many tokens",
+            "too large for model",
+        ]
+        .iter()
+        .any(|phrase| message.contains(phrase))
+    }
+
+    /// A short title for the kind of failure.
+    fn kind(&self) -> &'static str {
+        match self {
+            error if error.is_context_length() => "Conversation too long",
+            ApiError::Status { status, .. } => match status.as_u16() {
+                401 | 403 => "Authentication error",
+                404 => "Endpoint not found",
+                429 => "Rate limited",
+                500..=599 => "Server error",
+                _ => "Request rejected",
+            },
+            ApiError::InvalidUrl(_) => "Invalid URL",
+            ApiError::Network(_) => "Network error",
+            ApiError::ResponseTooLarge { .. } => "Response too large",
+        }
+    }
+
+    /// A suggestion for fixing the problem, shown alongside the error.
+    fn diagnosis(&self) -> &'static str {
+        match self {
+            error if error.is_context_length() => {
+                "The request is longer than the model accepts. Start a new conversation, or lower window or max_context_tokens."
+            }
+            ApiError::Status { status, .. } => match status.as_u16() {
+                401 | 403 => "The API key was rejected. Check that MISTRAL_API_KEY is set to a valid key.",
+                404 => "The endpoint was not found. Check that base_url points at the API root (e.g. https://api.mistral.ai).",
+                429 => "Rate limit reached. Wait a moment before trying again.",
+                500..=599 => "The server had a problem. Try again shortly.",
+                _ => "The server rejected the request.",
+            },
+            ApiError::InvalidUrl(_) => "The base_url is not a valid URL. It should look like https://api.mistral.ai.",
+            ApiError::Network(_) => {
+                "Could not reach the server. Check your internet connection, any proxy settings (HTTPS_PROXY) and the base_url."
+            }
+            ApiError::ResponseTooLarge { .. } => {
+                "The server sent far more data than expected. Check the base_url, or raise max_response_bytes if this is intended."
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Status { status, message } if message.is_empty() => write!(f, "API returned {}", status),
+            ApiError::Status { status, message } => write!(f, "API returned {}: {}", status, message),
+            ApiError::InvalidUrl(url) => write!(f, "invalid API URL `{}`", url),
+            ApiError::Network(e) => write!(f, "network error: {}", e),
+            ApiError::ResponseTooLarge { limit } => {
+                write!(f, "response exceeded the {} byte limit", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_builder() {
+            ApiError::InvalidUrl(error.url().map(|url| url.to_string()).unwrap_or_default())
+        } else {
+            ApiError::Network(error)
+        }
+    }
+}
+
+/// Result of a connectivity check against the API.
+struct PingReport {
+    latency: Duration,
+    /// Whether the configured model appears in the server's model list.
+    model_listed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+/// Rules for sending demanding prompts to a larger model. A prompt that
+/// meets any of them goes to `model`; the rest use the client's own model.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteRules {
+    /// The larger model
+    model: String,
+    /// Prompts at least this many characters long
+    #[serde(default)]
+    min_chars: Option<usize>,
+    /// Prompts that include code
+    #[serde(default)]
+    code: bool,
+    /// Prompts mentioning any of these words, ignoring case
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// Prompts about any of these languages, detected as for highlighting
+    #[serde(default)]
+    languages: Vec<String>,
+}
+
+impl RouteRules {
+    /// Why `prompt` should go to the larger model, or `None` if it needn't.
+    fn reason(&self, prompt: &str) -> Option<String> {
+        let length = prompt.chars().count();
+        if self.min_chars.is_some_and(|min| length >= min) {
+            return Some(format!("{} characters long", length));
+        }
+        if self.code && looks_like_code(prompt) {
+            return Some("contains code".to_string());
+        }
+        let lowercase = prompt.to_lowercase();
+        if let Some(keyword) = self.keywords.iter().find(|keyword| lowercase.contains(&keyword.to_lowercase())) {
+            return Some(format!("mentions \"{}\"", keyword));
+        }
+        MistralClient::extract_language_hint(prompt)
+            .filter(|language| self.languages.iter().any(|wanted| wanted.eq_ignore_ascii_case(language)))
+            .map(|language| format!("about {}", language))
+    }
+}
+
+/// Whether `text` includes code: a fenced block, or a few lines ending the
+/// way statements and blocks do in C-like languages.
+fn looks_like_code(text: &str) -> bool {
+    text.contains("```")
+        || text
+            .lines()
+            .filter(|line| line.trim_end().ends_with([';', '{', '}']))
+            .count()
+            >= 2
 }
 
 struct MistralClient {
     client: reqwest::Client,
     api_key: String,
+    auth: AuthScheme,
+    base_url: String,
+    /// Path of the text-completion endpoint used by `complete`
+    completions_path: String,
+    model: String,
+    /// Picks a larger model for some prompts when set
+    route: Option<RouteRules>,
+    params: Params,
+    logit_bias: Option<HashMap<String, f32>>,
+    max_response_bytes: usize,
+    /// Gzip request bodies over `COMPRESS_ABOVE_BYTES`
+    compress_requests: bool,
+    /// Payloads that end a stream, besides `STREAM_DONE_MARKER`
+    stream_done_markers: Vec<String>,
+    /// Where each turn's usage is reported, if anywhere
+    metrics: Option<MetricsSink>,
 }
 
 impl MistralClient {
     fn new(api_key: String) -> Self {
         let client = reqwest::Client::new();
-        Self { client, api_key }
+        Self {
+            client,
+            api_key,
+            auth: AuthScheme::default(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            completions_path: DEFAULT_COMPLETIONS_PATH.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            route: None,
+            params: Params::default(),
+            logit_bias: None,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            compress_requests: false,
+            stream_done_markers: Vec::new(),
+            metrics: None,
+        }
+    }
+
+    /// Limits how large a response body may be before the request fails.
+    fn with_max_response_bytes(mut self, limit: usize) -> Self {
+        self.max_response_bytes = limit;
+        self
+    }
+
+    /// Gzips large request bodies, e.g. prompts with pasted files. Only
+    /// for servers that accept `Content-Encoding: gzip` on requests, which
+    /// many don't.
+    fn with_request_compression(mut self) -> Self {
+        self.compress_requests = true;
+        self
+    }
+
+    /// Also ends a streamed reply at any of `markers`, for servers that
+    /// finish with something other than `[DONE]`.
+    fn with_stream_done_markers(mut self, markers: Vec<String>) -> Self {
+        self.stream_done_markers = markers;
+        self
+    }
+
+    /// Reports the model, latency, token usage and cost of every turn to
+    /// `sink`.
+    fn with_metrics(mut self, sink: MetricsSink) -> Self {
+        self.metrics = Some(sink);
+        self
+    }
+
+    /// Points the client at a different API root, such as a proxy or an
+    /// OpenAI-compatible server. Paths like `/v1/models` are appended to it.
+    fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Uses another path under the API root for `complete`, for servers
+    /// that put the text-completion endpoint somewhere else.
+    fn with_completions_path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        self.completions_path = if path.starts_with('/') { path } else { format!("/{}", path) };
+        self
+    }
+
+    /// Sends the API key the way a gateway expects, instead of as a bearer
+    /// token.
+    fn with_auth_scheme(mut self, scheme: AuthScheme) -> Self {
+        self.auth = scheme;
+        self
+    }
+
+    fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Biases generation towards (positive) or away from (negative) the
+    /// given token ids, for constrained generation. Values run from -100,
+    /// which in effect bans a token, to 100. Not every server accepts this.
+    fn with_logit_bias(mut self, bias: HashMap<String, f32>) -> Result<Self> {
+        for (token, value) in &bias {
+            if token.parse::<u32>().is_err() {
+                anyhow::bail!("logit_bias keys must be token ids, got `{}`", token);
+            }
+            if !(-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS).contains(value) {
+                anyhow::bail!(
+                    "logit_bias for token {} is {}, outside -{} to {}",
+                    token,
+                    value,
+                    MAX_LOGIT_BIAS,
+                    MAX_LOGIT_BIAS
+                );
+            }
+        }
+        self.logit_bias = (!bias.is_empty()).then_some(bias);
+        Ok(self)
+    }
+
+    /// Sends prompts that meet `rules` to a larger model, and the rest to
+    /// the client's model.
+    fn with_auto_route(mut self, rules: RouteRules) -> Self {
+        self.route = Some(rules);
+        self
+    }
+
+    /// The model a request ending in `prompt` goes to, with the reason when
+    /// routing chose the larger model.
+    fn route(&self, prompt: &str) -> (&str, Option<String>) {
+        match &self.route {
+            Some(rules) => match rules.reason(prompt) {
+                Some(reason) => (&rules.model, Some(reason)),
+                None => (&self.model, None),
+            },
+            None => (&self.model, None),
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn auth_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        match &self.auth {
+            AuthScheme::Bearer => {
+                headers.insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
+                );
+            }
+            AuthScheme::Header(name) => {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("`{}` is not a valid header name for the API key", name))?;
+                headers.insert(name, HeaderValue::from_str(&self.api_key)?);
+            }
+            AuthScheme::None => {}
+        }
+        Ok(headers)
+    }
+
+    /// Reads a response body, giving up as soon as it grows past
+    /// `max_response_bytes` instead of buffering all of it.
+    async fn read_body(&self, mut response: reqwest::Response) -> Result<Vec<u8>> {
+        let limit = self.max_response_bytes;
+        if response.content_length().is_some_and(|length| length > limit as u64) {
+            return Err(ApiError::ResponseTooLarge { limit }.into());
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(ApiError::from)? {
+            if body.len() + chunk.len() > limit {
+                return Err(ApiError::ResponseTooLarge { limit }.into());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Reads a streamed (server-sent events) reply, passing each piece of
+    /// text to `on_delta`, and returns the whole reply with the token usage
+    /// if the server included it.
+    async fn read_stream(
+        &self,
+        mut response: reqwest::Response,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, Option<Usage>)> {
+        let limit = self.max_response_bytes;
+        let mut received = 0;
+        let mut decoder = SseDecoder::new(&self.stream_done_markers);
+        let mut content = String::new();
+        let mut usage = None;
+
+        while let Some(chunk) = response.chunk().await.map_err(ApiError::from)? {
+            received += chunk.len();
+            if received > limit {
+                return Err(ApiError::ResponseTooLarge { limit }.into());
+            }
+
+            for payload in decoder.push(&chunk) {
+                if payload == STREAM_DONE_MARKER || self.stream_done_markers.contains(&payload) {
+                    return Ok((content, usage));
+                }
+                // Anything that isn't JSON, such as a keep-alive message, has
+                // nothing for us
+                if !payload.starts_with('{') {
+                    continue;
+                }
+                let event: StreamChunk = serde_json::from_str(&payload)?;
+                usage = event.usage.or(usage);
+                let text = event
+                    .choices
+                    .first()
+                    .and_then(|choice| choice.delta.content.as_deref())
+                    .unwrap_or_default();
+                if !text.is_empty() {
+                    on_delta(text);
+                    content.push_str(text);
+                }
+            }
+        }
+
+        Ok((content, usage))
+    }
+
+    /// Turns a non-success response into an `ApiError`, passing successful
+    /// ones through.
+    async fn check_status(&self, response: reqwest::Response) -> Result<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = self.read_body(response).await?;
+        Err(ApiError::from_response(status, &String::from_utf8_lossy(&body)).into())
+    }
+
+    /// Reads the body of a successful response, or fails with an `ApiError`.
+    async fn checked_body(&self, response: reqwest::Response) -> Result<Vec<u8>> {
+        let response = self.check_status(response).await?;
+        self.read_body(response).await
+    }
+
+    /// Checks connectivity and authentication by listing the available
+    /// models, which is cheap and doesn't use any tokens.
+    async fn ping(&self) -> Result<PingReport> {
+        let started = std::time::Instant::now();
+        let response = self
+            .client
+            .get(self.endpoint("/v1/models"))
+            .headers(self.auth_headers()?)
+            .send()
+            .await
+            .map_err(ApiError::from)?;
+        let latency = started.elapsed();
+
+        let models: ModelList = serde_json::from_slice(&self.checked_body(response).await?)?;
+        let model_listed = models.data.iter().any(|model| model.id == self.model);
+        Ok(PingReport { latency, model_listed })
+    }
+
+    /// Roughly how many tokens `messages` come to, worked out locally
+    /// without a request. This is an estimate (about four characters per
+    /// token, plus a few per message for the role and formatting), good
+    /// for budgeting but not exact: real counts depend on the model's
+    /// tokenizer and the language.
+    fn estimate_tokens<'a>(messages: impl IntoIterator<Item = &'a ChatMessage>) -> usize {
+        messages
+            .into_iter()
+            .map(|msg| msg.content.chars().count().div_ceil(4) + TOKENS_PER_MESSAGE)
+            .sum()
     }
 
     fn extract_language_hint(input: &str) -> Option<String> {
@@ -190,77 +898,850 @@ impl MistralClient {
     }
 
     async fn send_message(&self, messages: Vec<ChatMessage>) -> Result<(String, Option<String>)> {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", self.api_key))?,
-        );
-        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        self.send_message_with_params(messages, &self.params).await
+    }
+
+    /// Like `send_message`, but with explicit sampling parameters in place of
+    /// the client defaults.
+    async fn send_message_with_params(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: &Params,
+    ) -> Result<(String, Option<String>)> {
+        self.chat(messages, params, None).await
+    }
 
+    /// Like `send_message_with_params`, but streams the reply, calling
+    /// `on_delta` with each piece of text as it arrives. Still returns the
+    /// complete reply at the end.
+    async fn send_message_streaming(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: &Params,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, Option<String>)> {
+        self.chat(messages, params, Some(on_delta)).await
+    }
+
+    async fn chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: &Params,
+        on_delta: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(String, Option<String>)> {
         // Extract language hint from the last user message
         let language_hint = messages.last()
             .filter(|msg| msg.role == "user")
             .and_then(|msg| Self::extract_language_hint(&msg.content));
 
+        let prompt = messages
+            .last()
+            .filter(|msg| msg.role == "user")
+            .map_or("", |msg| msg.content.as_str());
+        let (model, _) = self.route(prompt);
         let request = ChatRequest {
-            model: "mistral-small".to_string(),
+            model: model.to_string(),
             messages,
+            params: params.clone(),
+            logit_bias: self.logit_bias.clone(),
+            stream: on_delta.is_some(),
         };
 
+        let started = std::time::Instant::now();
+        let (content, usage) = match on_delta {
+            Some(on_delta) => {
+                let response = self.post_chat(&request).await?;
+                let response = self.check_status(response).await?;
+                let (content, usage) = self.read_stream(response, on_delta).await?;
+                (normalize_line_endings(&content).into_owned(), usage)
+            }
+            None => self.complete_request(&request).await?,
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.emit(&metrics.turn(&request.model, started.elapsed(), usage));
+        }
+        Ok((content, language_hint))
+    }
+
+    async fn post_chat(&self, request: &ChatRequest) -> Result<reqwest::Response> {
+        self.post("/v1/chat/completions", request).await
+    }
+
+    /// Posts `request` as JSON to `path` under the API root, compressed if
+    /// that is turned on and the body is large.
+    async fn post(&self, path: &str, request: &impl Serialize) -> Result<reqwest::Response> {
+        let mut headers = self.auth_headers()?;
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let (body, encoding) = encode_body(serde_json::to_vec(request)?, self.compress_requests)?;
+        if let Some(encoding) = encoding {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+
         let response = self
             .client
-            .post("https://api.mistral.ai/v1/chat/completions")
+            .post(self.endpoint(path))
             .headers(headers)
-            .json(&request)
+            .body(body)
             .send()
-            .await?
-            .json::<ChatResponse>()
-            .await?;
+            .await
+            .map_err(ApiError::from)?;
+        Ok(response)
+    }
 
-        Ok((response.choices[0].message.content.clone(), language_hint))
+    /// Sends a non-streaming request, returning the reply and the token
+    /// usage if the server reported it.
+    async fn complete_request(&self, request: &ChatRequest) -> Result<(String, Option<Usage>)> {
+        let response = self.post_chat(request).await?;
+        let response: ChatResponse = serde_json::from_slice(&self.checked_body(response).await?)?;
+        let choice = response
+            .choices
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("the API returned no choices"))?;
+        let content = normalize_line_endings(&choice.message.content).into_owned();
+        Ok((content, response.usage))
     }
+
+    /// Continues `prompt` using the older text-completion endpoint, for
+    /// self-hosted servers that don't offer chat. There is no conversation:
+    /// the prompt is sent as is, without a system prompt or any history,
+    /// and the generated text is returned.
+    async fn complete(&self, prompt: &str) -> Result<String> {
+        let request = CompletionRequest {
+            model: &self.model,
+            prompt,
+            params: &self.params,
+            logit_bias: self.logit_bias.as_ref(),
+        };
+        let response = self.post(&self.completions_path, &request).await?;
+        let response: CompletionResponse = serde_json::from_slice(&self.checked_body(response).await?)?;
+        let choice = response
+            .choices
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("the API returned no choices"))?;
+        Ok(normalize_line_endings(&choice.text).into_owned())
+    }
+
+    /// Sends every request at once and returns the replies in the same
+    /// order, each with how long it took. Routing doesn't apply: each
+    /// request names its model.
+    async fn send_batch(&self, requests: &[BatchRequest]) -> Vec<Result<BatchReply>> {
+        let replies = requests.iter().map(|batch| async move {
+            let request = ChatRequest {
+                model: batch.model.clone(),
+                messages: batch.messages.clone(),
+                params: self.params.merged_with(&batch.params),
+                logit_bias: self.logit_bias.clone(),
+                stream: false,
+            };
+            let started = std::time::Instant::now();
+            let (content, usage) = self.complete_request(&request).await?;
+            Ok(BatchReply {
+                content,
+                usage,
+                elapsed: started.elapsed(),
+            })
+        });
+        futures::future::join_all(replies).await
+    }
+}
+
+/// One request of a `MistralClient::send_batch` call.
+#[derive(Debug, Clone)]
+struct BatchRequest {
+    model: String,
+    params: Params,
+    messages: Vec<ChatMessage>,
 }
 
+/// The reply to a `BatchRequest`.
 #[derive(Debug)]
-struct TableCell {
+struct BatchReply {
     content: String,
-    alignment: Option<Alignment>,
+    usage: Option<Usage>,
+    elapsed: Duration,
 }
 
-#[derive(Debug)]
-struct TableRow {
-    cells: Vec<TableCell>,
+/// `[metrics]` section of the config file: where to report each turn's
+/// usage, for tracking spend centrally.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsConfig {
+    /// URL to POST each turn to as JSON
+    webhook: Option<String>,
+    /// `host:port` of a statsd server, sent to over UDP
+    statsd: Option<String>,
+    /// Price per million tokens of each model, for the cost
+    prices: HashMap<String, Price>,
 }
 
-#[derive(Debug)]
-struct Table {
-    headers: TableRow,
-    rows: Vec<TableRow>,
-    column_widths: Vec<usize>,
+/// What a model charges, in dollars per million tokens.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Price {
+    input: f64,
+    output: f64,
 }
 
-impl Table {
-    fn new(headers: Vec<(String, Option<Alignment>)>) -> Self {
-        let header_row = TableRow {
-            cells: headers.into_iter()
-                .map(|(content, alignment)| TableCell { content, alignment })
-                .collect(),
-        };
-        let num_columns = header_row.cells.len();
-        Table {
-            headers: header_row,
-            rows: Vec::new(),
-            column_widths: vec![0; num_columns],
+/// What is reported about each turn.
+#[derive(Debug, Serialize)]
+struct TurnMetric {
+    model: String,
+    latency_ms: u64,
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+    /// Dollars, when the model's price is configured and usage is known
+    cost: Option<f64>,
+}
+
+impl TurnMetric {
+    /// The metric as statsd lines: the latency as a timer, and tokens and
+    /// cost as counters, all named after the model. statsd counters are
+    /// whole numbers, so the cost is counted in millionths of a dollar.
+    fn statsd_lines(&self) -> String {
+        let model: String = self
+            .model
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let mut lines = vec![format!("mistral_small.{}.latency:{}|ms", model, self.latency_ms)];
+        if let Some(tokens) = self.prompt_tokens {
+            lines.push(format!("mistral_small.{}.prompt_tokens:{}|c", model, tokens));
+        }
+        if let Some(tokens) = self.completion_tokens {
+            lines.push(format!("mistral_small.{}.completion_tokens:{}|c", model, tokens));
+        }
+        if let Some(cost) = self.cost {
+            let micros = (cost * 1_000_000.0).round() as u64;
+            lines.push(format!("mistral_small.{}.cost_microdollars:{}|c", model, micros));
         }
+        lines.join("\n")
     }
+}
 
-    fn add_row(&mut self, cells: Vec<String>) {
-        let row = TableRow {
-            cells: cells.into_iter()
-                .enumerate()
-                .map(|(i, content)| {
-                    let alignment = self.headers.cells.get(i)
-                        .and_then(|header| header.alignment);
+/// Sends a metric for every turn to a webhook and/or statsd. Sending
+/// happens in the background and failures are ignored, so metrics can
+/// never hold up or break the chat.
+struct MetricsSink {
+    client: reqwest::Client,
+    webhook: Option<String>,
+    statsd: Option<String>,
+    prices: HashMap<String, Price>,
+}
+
+impl MetricsSink {
+    /// A sink for the configured endpoints, or `None` if there are none.
+    fn new(config: &MetricsConfig) -> Option<Self> {
+        if config.webhook.is_none() && config.statsd.is_none() {
+            return None;
+        }
+        Some(Self {
+            client: reqwest::Client::new(),
+            webhook: config.webhook.clone(),
+            statsd: config.statsd.clone(),
+            prices: config.prices.clone(),
+        })
+    }
+
+    fn turn(&self, model: &str, elapsed: Duration, usage: Option<Usage>) -> TurnMetric {
+        let cost = self.prices.get(model).zip(usage).map(|(price, usage)| {
+            (usage.prompt_tokens as f64 * price.input + usage.completion_tokens as f64 * price.output) / 1_000_000.0
+        });
+        TurnMetric {
+            model: model.to_string(),
+            latency_ms: elapsed.as_millis() as u64,
+            prompt_tokens: usage.map(|usage| usage.prompt_tokens),
+            completion_tokens: usage.map(|usage| usage.completion_tokens),
+            cost,
+        }
+    }
+
+    fn emit(&self, metric: &TurnMetric) {
+        if let Some(url) = &self.webhook {
+            let request = self.client.post(url).timeout(Duration::from_secs(5)).json(metric);
+            tokio::spawn(async move {
+                let _ = request.send().await;
+            });
+        }
+        if let Some(addr) = self.statsd.clone() {
+            let lines = metric.statsd_lines();
+            tokio::spawn(async move {
+                if let Ok(socket) = tokio::net::UdpSocket::bind("0.0.0.0:0").await {
+                    let _ = socket.send_to(lines.as_bytes(), addr).await;
+                }
+            });
+        }
+    }
+}
+
+/// A conversation as it is persisted by a `SessionStore`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Conversation {
+    id: String,
+    messages: Vec<ChatMessage>,
+}
+
+/// Storage for saved conversations. `FileSessionStore` is the default;
+/// other backends (a database, an encrypted store) can be given to
+/// `ChatSession::with_store` instead.
+trait SessionStore: Send {
+    fn save(&self, conversation: &Conversation) -> Result<()>;
+    fn load(&self, id: &str) -> Result<Conversation>;
+    /// Ids of every stored conversation, sorted.
+    fn list(&self) -> Result<Vec<String>>;
+    /// Id of the conversation saved most recently, if any.
+    fn latest(&self) -> Result<Option<String>>;
+}
+
+/// Keeps each conversation as a JSON file named after its id.
+struct FileSessionStore {
+    dir: PathBuf,
+}
+
+impl FileSessionStore {
+    fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `~/.mistral_sessions`, next to the command history.
+    fn default_dir() -> PathBuf {
+        dirs::home_dir()
+            .map(|mut path| {
+                path.push(".mistral_sessions");
+                path
+            })
+            .unwrap_or_else(|| ".mistral_sessions".into())
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        validate_session_id(id)?;
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn save(&self, conversation: &Conversation) -> Result<()> {
+        let path = self.path(&conversation.id)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, serde_json::to_string_pretty(conversation)?)
+            .map_err(|e| anyhow::anyhow!("failed to write {}: {}", path.display(), e))
+    }
+
+    fn load(&self, id: &str) -> Result<Conversation> {
+        let path = self.path(id)?;
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("failed to read session `{}`: {}", id, e))?;
+        serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("invalid session file {}: {}", path.display(), e))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut ids: Vec<String> = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                let is_json = path.extension().is_some_and(|ext| ext == "json");
+                is_json.then(|| path.file_stem()?.to_str().map(str::to_string)).flatten()
+            })
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn latest(&self) -> Result<Option<String>> {
+        let mut latest = None;
+        for id in self.list()? {
+            // Skip stray files that aren't conversations we could load
+            let modified = self
+                .path(&id)
+                .ok()
+                .and_then(|path| std::fs::metadata(path).ok()?.modified().ok());
+            let Some(modified) = modified else {
+                continue;
+            };
+            // Ties go to the later id, which for generated ids is the newer one
+            if latest.as_ref().is_none_or(|(time, _)| modified >= *time) {
+                latest = Some((modified, id));
+            }
+        }
+        Ok(latest.map(|(_, id)| id))
+    }
+}
+
+/// Session ids double as file names, so keep them to a safe character set.
+fn validate_session_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("Session names may only use letters, digits, '-' and '_' (up to 64 characters)");
+    }
+    Ok(())
+}
+
+/// A fresh id for a new conversation, based on the current time down to
+/// the microsecond, so `/new` or `/fork` in quick succession never reuse
+/// an id. Ids still sort by the time they were made.
+fn new_session_id() -> String {
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    // Never hand out the same microsecond twice
+    static LAST: AtomicI64 = AtomicI64::new(0);
+    let now = chrono::Utc::now().timestamp_micros();
+    let previous = LAST
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+        .unwrap_or_default();
+    let time = chrono::DateTime::from_timestamp_micros(now.max(previous + 1)).unwrap_or_default();
+    time.with_timezone(&chrono::Local).format("session-%Y%m%d-%H%M%S-%6f").to_string()
+}
+
+/// A conversation with the model: the client plus the message history that
+/// is sent along with every request.
+struct ChatSession {
+    client: MistralClient,
+    messages: Vec<ChatMessage>,
+    /// When set, only this many earlier turns are sent with each request.
+    /// The full history is still kept in `messages`.
+    window: Option<usize>,
+    /// Sent as the first message of every request, ahead of the history.
+    system_prompt: Option<String>,
+    /// Few-shot example turns, sent after the system prompt and never
+    /// trimmed away.
+    examples: Vec<ChatMessage>,
+    /// Language for untagged code blocks, used instead of guessing from
+    /// each message.
+    language: Option<String>,
+    /// Estimated token budget for each request; older turns beyond it
+    /// are left out.
+    max_context_tokens: Option<usize>,
+    /// Set when the last request was too long for the model and had to be
+    /// sent again without the oldest turns.
+    context_trimmed: bool,
+    /// Number of messages and estimated tokens in the last request sent,
+    /// for `/stats`.
+    last_request: Option<(usize, usize)>,
+    /// Most messages kept in `messages`, not counting system messages;
+    /// the oldest turns beyond it are forgotten.
+    max_messages: Option<usize>,
+    /// Set when `messages` came from `load`. The first time the cap
+    /// forgets any of them the conversation moves to a new id, so the
+    /// saved file it was resumed from keeps its full history.
+    resumed: bool,
+    /// Id the conversation is saved under.
+    id: String,
+    store: Option<Box<dyn SessionStore>>,
+}
+
+impl ChatSession {
+    fn new(client: MistralClient) -> Self {
+        Self {
+            client,
+            messages: Vec::new(),
+            window: None,
+            system_prompt: None,
+            examples: Vec::new(),
+            language: None,
+            max_context_tokens: None,
+            context_trimmed: false,
+            last_request: None,
+            max_messages: None,
+            resumed: false,
+            id: new_session_id(),
+            store: None,
+        }
+    }
+
+    /// Persists the conversation through `store` whenever `save` is called.
+    fn with_store(mut self, store: Box<dyn SessionStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Saves the conversation to the store, if there is one. Empty
+    /// conversations are not saved.
+    fn save(&self) -> Result<()> {
+        match &self.store {
+            Some(store) if !self.messages.is_empty() => store.save(&Conversation {
+                id: self.id.clone(),
+                messages: self.messages.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces the current conversation with a stored one.
+    fn load(&mut self, id: &str) -> Result<()> {
+        let store = self
+            .store
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Sessions are not being stored"))?;
+        let conversation = store.load(id)?;
+        self.id = conversation.id;
+        self.messages = conversation.messages;
+        self.resumed = true;
+        Ok(())
+    }
+
+    /// Forgets the oldest whole turns until no more than `max_messages`
+    /// messages are left, not counting system messages, which are always
+    /// kept. The latest turn is kept however long it is. This bounds the
+    /// history held in memory and saved, separately from what is sent with
+    /// each request. A resumed conversation is moved to a new id before
+    /// it is first cut, leaving the file it came from as it was.
+    fn enforce_max_messages(&mut self) {
+        let Some(max) = self.max_messages else {
+            return;
+        };
+        let remaining = |from: usize| self.messages[from..].iter().filter(|msg| msg.role != "system").count();
+        let turn_starts: Vec<usize> = (0..self.messages.len())
+            .filter(|&i| self.messages[i].role == "user")
+            .collect();
+        let Some(&cut) = turn_starts.iter().find(|&&i| remaining(i) <= max).or(turn_starts.last()) else {
+            return;
+        };
+        let before = self.messages.len();
+        let mut index = 0;
+        self.messages.retain(|msg| {
+            let keep = index >= cut || msg.role == "system";
+            index += 1;
+            keep
+        });
+        if self.resumed && self.messages.len() < before {
+            self.id = new_session_id();
+            self.resumed = false;
+        }
+    }
+
+    /// Replaces the current conversation with the one saved most recently.
+    /// Returns false, leaving the conversation alone, if none is stored or
+    /// conversations aren't being stored at all.
+    fn load_latest(&mut self) -> Result<bool> {
+        let Some(store) = &self.store else {
+            return Ok(false);
+        };
+        match store.latest()? {
+            Some(id) => self.load(&id).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Saves the conversation under a new id and carries on as that copy,
+    /// so later turns go to the fork and the original is left as it was.
+    fn fork(&mut self, id: &str) -> Result<()> {
+        let store = self
+            .store
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Sessions are not being stored"))?;
+        validate_session_id(id)?;
+        if self.messages.is_empty() {
+            anyhow::bail!("There is nothing to fork yet");
+        }
+        if store.list()?.iter().any(|saved| saved == id) {
+            anyhow::bail!("A conversation called `{}` is already saved", id);
+        }
+        // Make sure the original is on disk as it stands before moving away
+        self.save()?;
+        let original = std::mem::replace(&mut self.id, id.to_string());
+        if let Err(e) = self.save() {
+            self.id = original;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Ids of the stored conversations.
+    fn list_saved(&self) -> Result<Vec<String>> {
+        match &self.store {
+            Some(store) => store.list(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The messages to send with the next request: the system prompt, the
+    /// few-shot examples and any other system messages, then at most
+    /// `window` earlier turns, then the current message. With
+    /// `max_context_tokens`, the oldest of those turns are left out until
+    /// the estimate fits.
+    fn request_messages(&self) -> Vec<ChatMessage> {
+        let prefix: Vec<ChatMessage> = self
+            .system_prompt
+            .iter()
+            .map(|prompt| ChatMessage {
+                role: "system".to_string(),
+                content: prompt.clone(),
+                created_at: None,
+            })
+            .chain(self.examples.iter().cloned())
+            .collect();
+
+        if self.window.is_none() && self.max_context_tokens.is_none() {
+            return prefix.into_iter().chain(self.messages.iter().cloned()).collect();
+        }
+
+        let (system, conversation): (Vec<&ChatMessage>, Vec<&ChatMessage>) =
+            self.messages.iter().partition(|msg| msg.role == "system");
+        let Some((current, history)) = conversation.split_last() else {
+            return prefix.into_iter().chain(self.messages.iter().cloned()).collect();
+        };
+
+        // Walk back over whole turns, each starting at a user message
+        let mut start = 0;
+        if let Some(window) = self.window {
+            start = history.len();
+            let mut turns = 0;
+            while start > 0 && turns < window {
+                start -= 1;
+                if history[start].role == "user" {
+                    turns += 1;
+                }
+            }
+        }
+
+        // Then drop whole turns from the front until the request fits
+        if let Some(budget) = self.max_context_tokens {
+            let fixed = MistralClient::estimate_tokens(prefix.iter().chain(system.iter().copied()))
+                + MistralClient::estimate_tokens([*current]);
+            while start < history.len()
+                && fixed + MistralClient::estimate_tokens(history[start..].iter().copied()) > budget
+            {
+                start += 1;
+                while start < history.len() && history[start].role != "user" {
+                    start += 1;
+                }
+            }
+        }
+
+        prefix
+            .into_iter()
+            .chain(
+                system
+                    .into_iter()
+                    .chain(history[start..].iter().copied())
+                    .chain(std::iter::once(*current))
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// Sends `input` as the next user message and records the reply.
+    /// Returns the reply with the language hint taken from `input`.
+    async fn send(&mut self, input: &str) -> Result<(String, Option<String>)> {
+        self.send_with(input, None).await
+    }
+
+    /// Like `send`, but streams the reply through `on_delta` as it arrives.
+    async fn send_streaming(
+        &mut self,
+        input: &str,
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<(String, Option<String>)> {
+        self.send_with(input, Some(on_delta)).await
+    }
+
+    async fn send_with(
+        &mut self,
+        input: &str,
+        on_delta: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(String, Option<String>)> {
+        self.messages.push(ChatMessage::now("user", normalize_line_endings(input).into_owned()));
+
+        match self.request(None, on_delta).await {
+            Ok((response, language_hint)) => {
+                self.messages.push(ChatMessage::now("assistant", response.clone()));
+                self.enforce_max_messages();
+                Ok((response, language_hint))
+            }
+            Err(e) => {
+                // Don't leave an unanswered user message in the history
+                self.messages.pop();
+                Err(e)
+            }
+        }
+    }
+
+    /// Drops the last assistant reply and asks the model again, optionally
+    /// overriding sampling parameters for this request only. The previous
+    /// reply is kept if the new request fails.
+    async fn regenerate_last(&mut self, params_override: Option<Params>) -> Result<String> {
+        if self.messages.last().map(|msg| msg.role.as_str()) != Some("assistant") {
+            anyhow::bail!("There is no reply to regenerate yet");
+        }
+        let previous = self.messages.pop();
+
+        let params = match &params_override {
+            Some(overrides) => self.client.params.merged_with(overrides),
+            None => self.client.params.clone(),
+        };
+
+        match self.request(Some(&params), None).await {
+            Ok((response, _)) => {
+                self.messages.push(ChatMessage::now("assistant", response.clone()));
+                Ok(response)
+            }
+            Err(e) => {
+                self.messages.extend(previous);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends `request_messages`, with `params` in place of the client's if
+    /// given. If the server says the request is longer than the model's
+    /// context, the token budget is lowered so older turns are left out and
+    /// the request is tried once more; `context_trimmed` records this.
+    async fn request(
+        &mut self,
+        params: Option<&Params>,
+        mut on_delta: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(String, Option<String>)> {
+        self.context_trimmed = false;
+        let result = self
+            .send_request(params, on_delta.as_mut().map(|f| &mut **f as &mut dyn FnMut(&str)))
+            .await;
+        match result {
+            Err(e) if e.downcast_ref::<ApiError>().is_some_and(ApiError::is_context_length) => {
+                if !self.shrink_context() {
+                    return Err(e);
+                }
+                self.context_trimmed = true;
+                self.send_request(params, on_delta).await
+            }
+            result => result,
+        }
+    }
+
+    async fn send_request(
+        &mut self,
+        params: Option<&Params>,
+        on_delta: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(String, Option<String>)> {
+        let messages = self.request_messages();
+        self.last_request = Some((messages.len(), MistralClient::estimate_tokens(&messages)));
+        match (on_delta, params) {
+            (Some(on_delta), params) => {
+                self.client
+                    .send_message_streaming(messages, params.unwrap_or(&self.client.params), on_delta)
+                    .await
+            }
+            (None, Some(params)) => self.client.send_message_with_params(messages, params).await,
+            (None, None) => self.client.send_message(messages).await,
+        }
+    }
+
+    /// Halves the token budget for requests, so the oldest turns are left
+    /// out. System messages and the current message always stay. Returns
+    /// false, leaving the budget alone, if there are no turns left to drop.
+    fn shrink_context(&mut self) -> bool {
+        let sent = self.request_messages().len();
+        let previous = self.max_context_tokens;
+        let budget = MistralClient::estimate_tokens(&self.request_messages()) / 2;
+        self.max_context_tokens = Some(previous.map_or(budget, |current| current.min(budget)));
+        if self.request_messages().len() < sent {
+            return true;
+        }
+        self.max_context_tokens = previous;
+        false
+    }
+
+    /// The language for untagged code blocks in the reply to `input`: the
+    /// session default if there is one, otherwise a guess from `input`.
+    fn language_hint(&self, input: &str) -> Option<String> {
+        self.language
+            .clone()
+            .or_else(|| MistralClient::extract_language_hint(input))
+    }
+
+    fn last_reply(&self) -> Option<&str> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|msg| msg.role == "assistant")
+            .map(|msg| msg.content.as_str())
+    }
+
+    /// Reply number `index` (1-based, as listed by `/history`), or an error
+    /// if there is no such message or it isn't a reply.
+    fn reply(&self, index: usize) -> Result<&ChatMessage> {
+        let message = index
+            .checked_sub(1)
+            .and_then(|i| self.messages.get(i))
+            .ok_or_else(|| anyhow::anyhow!("There is no message {}; the conversation has {}", index, self.messages.len()))?;
+        if message.role != "assistant" {
+            anyhow::bail!("Message {} is a {} message; pick one of the replies", index, message.role);
+        }
+        Ok(message)
+    }
+
+    /// Rewinds the conversation to message `index` (1-based, as listed by
+    /// `/history`), which must be a reply, dropping everything after it.
+    /// Returns how many messages were dropped.
+    fn rewind_to(&mut self, index: usize) -> Result<usize> {
+        self.reply(index)?;
+        let dropped = self.messages.len() - index;
+        self.messages.truncate(index);
+        Ok(dropped)
+    }
+
+    /// The most recent user message, if any.
+    fn last_user_message(&self) -> Option<&str> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|msg| msg.role == "user")
+            .map(|msg| msg.content.as_str())
+    }
+
+    /// Forgets the conversation so far.
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.resumed = false;
+        self.last_request = None;
+        self.id = new_session_id();
+    }
+}
+
+#[derive(Debug)]
+struct TableCell {
+    content: String,
+    alignment: Option<Alignment>,
+}
+
+#[derive(Debug)]
+struct TableRow {
+    cells: Vec<TableCell>,
+}
+
+#[derive(Debug)]
+struct Table {
+    headers: TableRow,
+    rows: Vec<TableRow>,
+    column_widths: Vec<usize>,
+}
+
+impl Table {
+    fn new(headers: Vec<(String, Option<Alignment>)>) -> Self {
+        let header_row = TableRow {
+            cells: headers.into_iter()
+                .map(|(content, alignment)| TableCell { content, alignment })
+                .collect(),
+        };
+        let num_columns = header_row.cells.len();
+        Table {
+            headers: header_row,
+            rows: Vec::new(),
+            column_widths: vec![0; num_columns],
+        }
+    }
+
+    fn add_row(&mut self, cells: Vec<String>) {
+        let row = TableRow {
+            cells: cells.into_iter()
+                .enumerate()
+                .map(|(i, content)| {
+                    let alignment = self.headers.cells.get(i)
+                        .and_then(|header| header.alignment);
                     TableCell { content, alignment }
                 })
                 .collect(),
@@ -282,14 +1763,14 @@ impl Table {
 
         // First pass: Calculate required width for each column
         for (i, cell) in self.headers.cells.iter().enumerate() {
-            let content_width = cell.content.chars().count();
+            let content_width = textwrap::core::display_width(&cell.content);
             self.column_widths[i] = self.column_widths[i].max(content_width);
         }
 
         for row in &self.rows {
             for (i, cell) in row.cells.iter().enumerate() {
                 if i < self.column_widths.len() {
-                    let content_width = cell.content.chars().count();
+                    let content_width = textwrap::core::display_width(&cell.content);
                     self.column_widths[i] = self.column_widths[i].max(content_width);
                 }
             }
@@ -396,13 +1877,24 @@ impl Table {
                 let mut current_line = String::new();
                 
                 for word in words {
+                    // A word wider than the column is broken across lines,
+                    // as prose is
+                    if textwrap::core::display_width(word) > width {
+                        if !current_line.is_empty() {
+                            lines.push(std::mem::take(&mut current_line));
+                        }
+                        let mut pieces = break_long_word(word, width);
+                        current_line = pieces.pop().unwrap_or_default();
+                        lines.extend(pieces);
+                        continue;
+                    }
                     let test_line = if current_line.is_empty() {
                         word.to_string()
                     } else {
                         format!("{} {}", current_line, word)
                     };
                     
-                    if test_line.chars().count() <= width {
+                    if textwrap::core::display_width(&test_line) <= width {
                         current_line = test_line;
                     } else {
                         if !current_line.is_empty() {
@@ -429,17 +1921,17 @@ impl Table {
             output.push_str("  │ ");
             for (i, (cell, wrapped_content)) in row.cells.iter().zip(&wrapped_contents).enumerate() {
                 let content = wrapped_content.get(line_idx).map_or("", |s| s);
+                // Padded by display width, so wide characters line up
+                let spaces = self.column_widths[i].saturating_sub(textwrap::core::display_width(content));
                 
                 let formatted = match cell.alignment {
-                    Some(Alignment::Left) | None => format!("{:<width$}", content, width = self.column_widths[i]),
-                    Some(Alignment::Right) => format!("{:>width$}", content, width = self.column_widths[i]),
+                    Some(Alignment::Left) | Some(Alignment::None) | None => format!("{}{}", content, " ".repeat(spaces)),
+                    Some(Alignment::Right) => format!("{}{}", " ".repeat(spaces), content),
                     Some(Alignment::Center) => {
-                        let spaces = self.column_widths[i] - content.chars().count();
                         let left_pad = spaces / 2;
                         let right_pad = spaces - left_pad;
                         format!("{}{}{}", " ".repeat(left_pad), content, " ".repeat(right_pad))
                     },
-                    Some(Alignment::None) => format!("{:<width$}", content, width = self.column_widths[i]),
                 };
                 
                 output.push_str(&formatted);
@@ -452,48 +1944,888 @@ impl Table {
     }
 }
 
-struct MarkdownRenderer {
-    syntax_set: SyntaxSet,
-    theme_set: ThemeSet,
-    wrap_options: Options<'static>,
-    // Table state
-    in_table: bool,
-    table_headers: Vec<String>,
-    current_row: Vec<String>,
-    table_rows: Vec<Vec<String>>,
-    table_alignments: Vec<Option<Alignment>>,
+/// Headers, column alignments and data rows of a markdown table.
+type ParsedTable = (Vec<String>, Vec<Option<Alignment>>, Vec<Vec<String>>);
+
+/// Inline text attributes set by SGR escape sequences, and any OSC 8
+/// hyperlink, tracked so a style that spans a line break can be closed
+/// before it and reopened after it.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct SgrState {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    /// Target of the hyperlink the text is inside
+    link: Option<String>,
 }
 
-impl MarkdownRenderer {
-    fn new(width: usize) -> Self {
-        let wrap_options = Options::new(width)
-            .initial_indent("  ")
-            .subsequent_indent("  ");
-            
-        Self {
-            syntax_set: SyntaxSet::load_defaults_newlines(),
-            theme_set: ThemeSet::load_defaults(),
-            wrap_options,
-            in_table: false,
-            table_headers: Vec::new(),
-            current_row: Vec::new(),
-            table_rows: Vec::new(),
-            table_alignments: Vec::new(),
+impl SgrState {
+    /// Updates the state from every SGR sequence (`ESC [ ... m`) and OSC 8
+    /// hyperlink (`ESC ] 8 ; params ; uri ST`) in `text`.
+    fn scan(&mut self, text: &str) {
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\x1B' {
+                continue;
+            }
+            match chars.peek() {
+                Some('[') => {
+                    chars.next();
+                    let mut params = String::new();
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7E').contains(&c) {
+                            if c == 'm' {
+                                self.apply(&params);
+                            }
+                            break;
+                        }
+                        params.push(c);
+                    }
+                }
+                Some(']') => {
+                    chars.next();
+                    // Ends at the string terminator (ESC \\) or BEL
+                    let mut command = String::new();
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1B' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                        command.push(c);
+                    }
+                    if let Some((_, uri)) = command.strip_prefix("8;").and_then(|rest| rest.split_once(';')) {
+                        self.link = (!uri.is_empty()).then(|| uri.to_string());
+                    }
+                }
+                _ => {}
+            }
         }
     }
 
-    fn render_table(&self) -> String {
-        if self.table_headers.is_empty() && self.table_rows.is_empty() {
-            return String::new();
+    fn apply(&mut self, params: &str) {
+        let mut codes = params.split(';');
+        while let Some(code) = codes.next() {
+            match code {
+                "" | "0" => {
+                    *self = SgrState {
+                        link: self.link.take(),
+                        ..SgrState::default()
+                    }
+                }
+                "1" => self.bold = true,
+                "2" => self.dim = true,
+                "3" => self.italic = true,
+                "4" => self.underline = true,
+                "9" => self.strikethrough = true,
+                "22" => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                "23" => self.italic = false,
+                "24" => self.underline = false,
+                "29" => self.strikethrough = false,
+                // Extended colours carry their own arguments, skip past them
+                "38" | "48" | "58" => match codes.next() {
+                    Some("5") => {
+                        codes.next();
+                    }
+                    Some("2") => {
+                        codes.nth(2);
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
         }
+    }
 
-        // Create table with headers and alignments
-        let headers: Vec<(String, Option<Alignment>)> = self.table_headers.iter().cloned()
-            .zip(self.table_alignments.iter().cloned())
-            .map(|(header, alignment)| (header.trim().to_string(), alignment))
-            .collect();
+    /// Escape sequences that switch on every active attribute.
+    fn open_codes(&self) -> String {
+        let mut codes = String::new();
+        for (active, code) in [
+            (self.bold, "\x1B[1m"),
+            (self.dim, "\x1B[2m"),
+            (self.italic, "\x1B[3m"),
+            (self.underline, "\x1B[4m"),
+            (self.strikethrough, "\x1B[9m"),
+        ] {
+            if active {
+                codes.push_str(code);
+            }
+        }
+        if let Some(uri) = &self.link {
+            codes.push_str(&hyperlink_start(uri));
+        }
+        codes
+    }
 
-        let mut table = Table::new(headers);
+    /// Escape sequences that switch off every active attribute, leaving any
+    /// surrounding colour alone.
+    fn close_codes(&self) -> String {
+        let mut codes = String::new();
+        for (active, code) in [
+            (self.bold || self.dim, "\x1B[22m"),
+            (self.italic, "\x1B[23m"),
+            (self.underline, "\x1B[24m"),
+            (self.strikethrough, "\x1B[29m"),
+            (self.link.is_some(), HYPERLINK_END),
+        ] {
+            if active {
+                codes.push_str(code);
+            }
+        }
+        codes
+    }
+}
+
+/// Closes an OSC 8 hyperlink.
+const HYPERLINK_END: &str = "\x1B]8;;\x1B\\";
+
+/// Opens an OSC 8 hyperlink to `uri`; text up to `HYPERLINK_END` is the link.
+fn hyperlink_start(uri: &str) -> String {
+    format!("\x1B]8;;{}\x1B\\", uri)
+}
+
+/// Best guess at whether the terminal understands OSC 8 hyperlinks. There
+/// is no way to ask, so this goes by the variables terminals that support
+/// them are known to set; others would show the escape codes.
+fn terminal_supports_hyperlinks() -> bool {
+    let var = |name: &str| env::var(name).unwrap_or_default();
+    if ["WT_SESSION", "KITTY_WINDOW_ID", "WEZTERM_EXECUTABLE"].iter().any(|name| env::var_os(name).is_some()) {
+        return true;
+    }
+    if matches!(var("TERM_PROGRAM").as_str(), "iTerm.app" | "WezTerm" | "vscode" | "ghostty" | "Hyper") {
+        return true;
+    }
+    if var("VTE_VERSION").parse::<u32>().is_ok_and(|version| version >= 5000) {
+        return true;
+    }
+    let term = var("TERM");
+    ["kitty", "alacritty", "foot", "ghostty"].iter().any(|name| term.contains(name))
+}
+
+/// Ends each piece of a word that was too long for a line and had to be
+/// broken, so it reads as continuing on the next line.
+const CONTINUATION_MARKER: char = '↩';
+
+/// Breaks `word` into pieces at most `width` columns wide, each but the last
+/// ending in `CONTINUATION_MARKER`. Escape sequences are kept whole and take
+/// up no room.
+fn break_long_word(word: &str, width: usize) -> Vec<String> {
+    let room = width.saturating_sub(1).max(1);
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut piece_width = 0;
+    let mut chars = word.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1B' {
+            piece.push(ch);
+            match chars.next() {
+                Some('[') => {
+                    piece.push('[');
+                    for ch in chars.by_ref() {
+                        piece.push(ch);
+                        if ('\x40'..='\x7E').contains(&ch) {
+                            break;
+                        }
+                    }
+                }
+                Some(']') => {
+                    piece.push(']');
+                    let mut last = ']';
+                    for ch in chars.by_ref() {
+                        piece.push(ch);
+                        if ch == '\x07' || (ch == '\\' && last == '\x1B') {
+                            break;
+                        }
+                        last = ch;
+                    }
+                }
+                Some(other) => piece.push(other),
+                None => {}
+            }
+            continue;
+        }
+
+        let ch_width = textwrap::core::display_width(ch.encode_utf8(&mut [0; 4]));
+        if piece_width > 0 && piece_width + ch_width > room && chars.peek().is_some() {
+            piece.push(CONTINUATION_MARKER);
+            pieces.push(std::mem::take(&mut piece));
+            piece_width = 0;
+        }
+        piece.push(ch);
+        piece_width += ch_width;
+    }
+    pieces.push(piece);
+    pieces
+}
+
+/// Breaks any word in `text` that is wider than `width` with
+/// `break_long_word`, so long URLs and encoded blobs wrap with a visible
+/// continuation rather than being cut silently.
+fn break_long_words(text: &str, width: usize) -> Cow<'_, str> {
+    let too_long = |word: &str| textwrap::core::display_width(word) > width;
+    if !text.split([' ', '\n']).any(too_long) {
+        return Cow::Borrowed(text);
+    }
+    let lines: Vec<String> = text
+        .split('\n')
+        .map(|line| {
+            line.split(' ')
+                .map(|word| if too_long(word) { break_long_word(word, width).join(" ") } else { word.to_string() })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect();
+    Cow::Owned(lines.join("\n"))
+}
+
+/// Wraps text that contains inline ANSI styling.
+///
+/// textwrap already ignores escape sequences when measuring and breaking
+/// words; on top of that, any style still open at the end of a line is
+/// closed there and reopened after the next line's indent, so every line
+/// stands on its own and nothing leaks into the indent or past the text.
+/// Words too long for a line are broken with a continuation marker.
+fn wrap_styled(text: &str, options: &Options<'_>) -> Vec<String> {
+    let indent = textwrap::core::display_width(options.initial_indent)
+        .max(textwrap::core::display_width(options.subsequent_indent));
+    let text = break_long_words(text, options.width.saturating_sub(indent));
+    restyle_lines(wrap(&text, options), options)
+}
+
+/// Like `wrap_styled`, but only breaks lines where `text` already has
+/// line breaks, however long they are. The indents still apply.
+fn unwrapped_styled(text: &str, options: &Options<'_>) -> Vec<String> {
+    let lines = text.split('\n').enumerate().map(|(i, line)| {
+        let indent = if i == 0 { options.initial_indent } else { options.subsequent_indent };
+        Cow::Owned(format!("{}{}", indent, line))
+    });
+    restyle_lines(lines.collect(), options)
+}
+
+/// Closes any style still open at the end of each of `lines` and reopens
+/// it after the next line's indent.
+fn restyle_lines(lines: Vec<Cow<'_, str>>, options: &Options<'_>) -> Vec<String> {
+    let mut state = SgrState::default();
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let indent = if i == 0 { options.initial_indent } else { options.subsequent_indent };
+            let body = line.strip_prefix(indent).unwrap_or(&line);
+
+            let mut styled = String::with_capacity(line.len() + 16);
+            styled.push_str(indent);
+            styled.push_str(&state.open_codes());
+            styled.push_str(body);
+            state.scan(body);
+            styled.push_str(&state.close_codes());
+            styled
+        })
+        .collect()
+}
+
+/// A run of streamed markdown that is complete enough to render on its own.
+#[derive(Debug, PartialEq)]
+struct StreamBlock {
+    markdown: String,
+    /// The block is a list item and the next block continues the same list,
+    /// so no blank line should separate them.
+    continues_list: bool,
+}
+
+/// Buffers a streamed reply and releases it in blocks that are safe to
+/// render, so prose appears a paragraph at a time and nothing is drawn
+/// half-formed.
+///
+/// Only complete lines are considered; text after the last newline is
+/// always held back. Within those lines a block is released:
+/// * at a blank line, ending a paragraph, table or list
+/// * when a new top-level list item starts, releasing the previous item
+/// * at the closing fence of a code block (nothing inside an open fence is
+///   released, however long it gets)
+/// * after a heading line
+///
+/// Anything else (a paragraph or table still being written) stays buffered
+/// until one of the above, or until `finish` is called at the end.
+#[derive(Debug, Default)]
+struct MarkdownStream {
+    pending: String,
+}
+
+impl MarkdownStream {
+    /// Adds streamed text and returns any blocks that are now complete.
+    fn push(&mut self, chunk: &str) -> Vec<StreamBlock> {
+        self.pending.push_str(chunk);
+
+        // Pending text always starts at a block boundary, so it can be
+        // rescanned from the top on every push.
+        let mut blocks = Vec::new();
+        let mut block_start = 0;
+        let mut offset = 0;
+        let mut fence: Option<(char, usize)> = None;
+        let mut in_list_item = false;
+
+        while let Some(newline) = self.pending[offset..].find('\n') {
+            let line_start = offset;
+            offset += newline + 1;
+            let line = self.pending[line_start..offset].trim_end();
+            let trimmed = line.trim_start();
+
+            if let Some((marker, length)) = fence {
+                let closing = trimmed.chars().take_while(|&c| c == marker).count();
+                if closing >= length && trimmed[closing..].trim().is_empty() {
+                    fence = None;
+                    in_list_item = false;
+                    blocks.push((block_start, offset, false));
+                    block_start = offset;
+                }
+                continue;
+            }
+
+            if let Some(opening) = Self::fence_marker(trimmed) {
+                fence = Some(opening);
+            } else if trimmed.is_empty() {
+                in_list_item = false;
+                blocks.push((block_start, offset, false));
+                block_start = offset;
+            } else if Self::is_list_item(line) {
+                if in_list_item && line_start > block_start {
+                    blocks.push((block_start, line_start, true));
+                    block_start = line_start;
+                }
+                in_list_item = true;
+            } else if trimmed.starts_with('#') && line_start == block_start {
+                blocks.push((block_start, offset, false));
+                block_start = offset;
+            }
+        }
+
+        let blocks: Vec<StreamBlock> = blocks
+            .into_iter()
+            .filter(|(start, end, _)| !self.pending[*start..*end].trim().is_empty())
+            .map(|(start, end, continues_list)| StreamBlock {
+                markdown: self.pending[start..end].to_string(),
+                continues_list,
+            })
+            .collect();
+        self.pending.drain(..block_start);
+        blocks
+    }
+
+    /// Returns whatever is still buffered once the stream has ended.
+    fn finish(&mut self) -> Option<StreamBlock> {
+        let markdown = std::mem::take(&mut self.pending);
+        (!markdown.trim().is_empty()).then_some(StreamBlock { markdown, continues_list: false })
+    }
+
+    /// The fence character and length if `line` opens a fenced code block.
+    fn fence_marker(line: &str) -> Option<(char, usize)> {
+        let marker = line.chars().next().filter(|&c| c == '`' || c == '~')?;
+        let length = line.chars().take_while(|&c| c == marker).count();
+        (length >= 3).then_some((marker, length))
+    }
+
+    /// Whether `line` starts a top-level list item.
+    fn is_list_item(line: &str) -> bool {
+        if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+            return true;
+        }
+        let digits = line.chars().take_while(char::is_ascii_digit).count();
+        digits > 0 && (line[digits..].starts_with(". ") || line[digits..].starts_with(") "))
+    }
+}
+
+/// A code block from a reply, without its fences.
+#[derive(Debug, Clone, PartialEq)]
+struct CodeBlock {
+    /// Language tag from the opening fence, if it had one
+    language: Option<String>,
+    code: String,
+}
+
+/// The code blocks in `markdown`, in the order they appear.
+fn extract_code_blocks(markdown: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<CodeBlock> = None;
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split(|c: char| c.is_whitespace() || c == ',')
+                        .next()
+                        .filter(|lang| !lang.is_empty())
+                        .map(str::to_string),
+                    CodeBlockKind::Indented => None,
+                };
+                current = Some(CodeBlock { language, code: String::new() });
+            }
+            Event::Text(text) => {
+                if let Some(block) = current.as_mut() {
+                    block.code.push_str(&text);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) => blocks.extend(current.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// Keywords that start a line of code, in lower case as prose never is at
+/// the start of a line.
+const CODE_KEYWORDS: [&str; 10] = ["fn ", "def ", "let ", "const ", "var ", "import ", "return ", "class ", "pub ", "func "];
+
+/// Whether a line is almost certainly code on its own: it ends like a
+/// statement or block in C-like languages, or starts with a keyword and
+/// has a bracket, assignment or colon after it.
+fn is_code_line(line: &str) -> bool {
+    let line = line.trim();
+    line.ends_with([';', '{', '}'])
+        || (CODE_KEYWORDS.iter().any(|keyword| line.starts_with(keyword)) && line.contains(['(', '=', ':']))
+}
+
+/// Whether a line can't be code because it is markdown structure or reads
+/// as a sentence.
+fn is_prose_line(line: &str) -> bool {
+    let line = line.trim();
+    let heading = line.starts_with('#') && line.trim_start_matches('#').starts_with(' ');
+    let list_item = line.starts_with(['-', '*', '+']) && line[1..].starts_with(' ')
+        || line.split_once(". ").is_some_and(|(number, _)| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+    let sentence = line.ends_with(['.', '!', '?']) && line.split_whitespace().count() >= 4;
+    heading || list_item || sentence || line.starts_with(['>', '|'])
+}
+
+/// Wraps code the model left unfenced in a fence, so it is highlighted
+/// instead of being wrapped as prose. A region is a run of lines that are
+/// code-like or indented continuations, with at least two lines that are
+/// unmistakably code and none that read as prose; anything less is left
+/// alone. The fence has no language, so the session's hint applies.
+fn fence_unfenced_code(text: &str) -> Cow<'_, str> {
+    let lines: Vec<&str> = text.lines().collect();
+    let continues = |line: &str| {
+        !line.trim().is_empty() && !is_prose_line(line) && (line.starts_with([' ', '\t']) || line.trim_start().starts_with(['}', ')', ']']))
+    };
+
+    let mut output = String::with_capacity(text.len());
+    let mut in_fence = false;
+    let mut fenced = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        let starts_region = !in_fence && is_code_line(line) && !is_prose_line(line);
+        if starts_region {
+            let end = (i..lines.len())
+                .find(|&j| !((is_code_line(lines[j]) && !is_prose_line(lines[j])) || continues(lines[j])))
+                .unwrap_or(lines.len());
+            if lines[i..end].iter().filter(|line| is_code_line(line)).count() >= 2 {
+                output.push_str("```\n");
+                for line in &lines[i..end] {
+                    output.push_str(line);
+                    output.push('\n');
+                }
+                output.push_str("```\n");
+                fenced = true;
+                i = end;
+                continue;
+            }
+        }
+        output.push_str(line);
+        output.push('\n');
+        i += 1;
+    }
+
+    if !fenced {
+        return Cow::Borrowed(text);
+    }
+    if !text.ends_with('\n') {
+        output.pop();
+    }
+    Cow::Owned(output)
+}
+
+/// Characters that have a Unicode superscript form, and those forms.
+const SUPERSCRIPTS: (&str, &str) = (
+    "0123456789+-=()abcdefghijklmnoprstuvwxyz",
+    "⁰¹²³⁴⁵⁶⁷⁸⁹⁺⁻⁼⁽⁾ᵃᵇᶜᵈᵉᶠᵍʰⁱʲᵏˡᵐⁿᵒᵖʳˢᵗᵘᵛʷˣʸᶻ",
+);
+
+/// Characters that have a Unicode subscript form, and those forms.
+const SUBSCRIPTS: (&str, &str) = ("0123456789+-=()aehijklmnoprstuvx", "₀₁₂₃₄₅₆₇₈₉₊₋₌₍₎ₐₑₕᵢⱼₖₗₘₙₒₚᵣₛₜᵤᵥₓ");
+
+/// Turns `H~2~O` and `x^2^`, as the subscript and superscript extensions
+/// to markdown write them, into Unicode subscripts and superscripts. A run
+/// with whitespace in it, or with any character that has no such form, is
+/// left as written, markers and all, and so is `~~` strikethrough.
+fn render_scripts(text: &str) -> Cow<'_, str> {
+    if !text.contains(['~', '^']) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['~', '^']) {
+        output.push_str(&rest[..start]);
+        let marker = rest[start..].chars().next().unwrap_or_default();
+        let markers = rest[start..].len() - rest[start..].trim_start_matches(marker).len();
+        if marker == '~' && markers > 1 {
+            output.push_str(&rest[start..start + markers]);
+            rest = &rest[start + markers..];
+            continue;
+        }
+        let (from, to) = if marker == '~' { SUBSCRIPTS } else { SUPERSCRIPTS };
+        let after = &rest[start + 1..];
+        let converted = after.find(marker).and_then(|end| {
+            let run = &after[..end];
+            let converted: Option<String> = run
+                .chars()
+                .map(|c| from.chars().position(|f| f == c).and_then(|i| to.chars().nth(i)))
+                .collect();
+            converted.filter(|_| !run.is_empty()).map(|converted| (converted, end))
+        });
+        match converted {
+            Some((converted, end)) => {
+                output.push_str(&converted);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // Keep the marker; the next one may still open a run
+                output.push(marker);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    Cow::Owned(output)
+}
+
+/// Expands `:name:` emoji shortcodes (as in `:tada:`) to the emoji itself.
+/// Unknown names are left as they are.
+fn expand_shortcodes(text: &str) -> Cow<'_, str> {
+    if !text.contains(':') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after.find(':').and_then(|end| {
+            let name = &after[..end];
+            let valid = !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'));
+            valid
+                .then(|| emojis::get_by_shortcode(name))
+                .flatten()
+                .map(|emoji| (emoji, end))
+        });
+        match emoji {
+            Some((emoji, end)) => {
+                output.push_str(emoji.as_str());
+                rest = &after[end + 1..];
+            }
+            None => {
+                // Keep the colon; the next one may still open a shortcode
+                output.push(':');
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    Cow::Owned(output)
+}
+
+/// Removes boilerplate openings such as "Sure, here's" from replies
+/// before they are shown. The conversation keeps the reply as it was sent.
+#[derive(Debug, Clone)]
+struct PrefixFilter {
+    patterns: Vec<Regex>,
+}
+
+impl PrefixFilter {
+    /// Compiles `patterns`, each anchored to the start of the reply.
+    fn new(patterns: &[String]) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})", pattern))
+                    .map_err(|e| anyhow::anyhow!("invalid strip_prefixes pattern `{}`: {}", pattern, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// `text` without any leading matches, capitalised if something was
+    /// removed so the reply still reads as a sentence.
+    fn strip<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut rest = text.trim_start();
+        // Keep going, as openings tend to come in pairs ("Sure! As an AI...")
+        while let Some(found) = self.patterns.iter().find_map(|pattern| pattern.find(rest).filter(|m| !m.is_empty())) {
+            rest = rest[found.end()..].trim_start();
+        }
+        if rest.len() == text.trim_start().len() {
+            return Cow::Borrowed(text);
+        }
+
+        let mut chars = rest.chars();
+        match chars.next() {
+            Some(first) if first.is_lowercase() => Cow::Owned(first.to_uppercase().chain(chars).collect()),
+            _ => Cow::Borrowed(rest),
+        }
+    }
+}
+
+/// What to do when the model declines to answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RefusalAction {
+    /// Show the reply as usual
+    #[default]
+    Off,
+    /// Mark the reply with a dim badge
+    Badge,
+    /// Regenerate the reply once, with the same question, and mark it if
+    /// it is declined again
+    Regenerate,
+}
+
+/// `[refusals]` section of the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RefusalConfig {
+    action: RefusalAction,
+    /// Regexes for the opening of a refusal, replacing the built-in ones
+    patterns: Option<Vec<String>>,
+}
+
+/// Openings of a typical refusal, matched case-insensitively.
+const DEFAULT_REFUSAL_PATTERNS: [&str; 3] = [
+    r"(I'm sorry|I apologi[sz]e|Sorry)?[,.!]?\s*(but\s+)?I\s*(can't|cannot|can not|won't|am unable to|'m unable to|am not able to|'m not able to)\s+(help|assist|comply|provide|do that|fulfil)",
+    r"I\s*(must|have to)\s+(decline|refuse)",
+    r"As an AI[^.]*,\s*I\s*(can't|cannot|am unable to)",
+];
+
+/// Shown under a reply the model declined to give.
+const REFUSAL_BADGE: &str = "  [model declined]";
+
+/// Spots replies where the model declined to answer. Only the first
+/// paragraph is checked, so a long answer that mentions what it can't do
+/// isn't taken for a refusal.
+#[derive(Debug, Clone)]
+struct RefusalDetector {
+    action: RefusalAction,
+    patterns: Vec<Regex>,
+}
+
+impl RefusalDetector {
+    fn new(config: &RefusalConfig) -> Result<Self> {
+        let defaults = DEFAULT_REFUSAL_PATTERNS.map(String::from);
+        let patterns = config
+            .patterns
+            .as_deref()
+            .unwrap_or(&defaults)
+            .iter()
+            .map(|pattern| {
+                Regex::new(&format!("(?i)^(?:{})", pattern))
+                    .map_err(|e| anyhow::anyhow!("invalid refusals pattern `{}`: {}", pattern, e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            action: config.action,
+            patterns,
+        })
+    }
+
+    /// Whether detection is on and `reply` opens with a refusal.
+    fn declined(&self, reply: &str) -> bool {
+        let opening = reply.trim_start().split("\n\n").next().unwrap_or_default();
+        self.action != RefusalAction::Off && self.patterns.iter().any(|pattern| pattern.is_match(opening))
+    }
+
+    /// Whether a declined reply should be regenerated.
+    fn regenerates(&self) -> bool {
+        self.action == RefusalAction::Regenerate
+    }
+}
+
+/// GitHub alert types: the marker inside `[!...]`, then the label, icon and
+/// colour of the callout.
+const CALLOUTS: [(&str, &str, &str, Color); 5] = [
+    ("NOTE", "Note", "ℹ", Color::Blue),
+    ("TIP", "Tip", "💡", Color::Green),
+    ("IMPORTANT", "Important", "❗", Color::Magenta),
+    ("WARNING", "Warning", "⚠", Color::Yellow),
+    ("CAUTION", "Caution", "🛑", Color::Red),
+];
+
+/// Draws a rendered blockquote with a bar down its left side. A quote that
+/// opens with a marker such as `[!NOTE]` becomes a labelled callout in that
+/// type's colour; any other quote, including unknown markers, gets a plain
+/// dim bar. `body` is already wrapped two columns narrower to make room.
+fn format_quote(body: &str) -> String {
+    let mut lines: Vec<String> = body.trim_end_matches('\n').lines().map(str::to_string).collect();
+
+    let callout = lines.first().and_then(|first| {
+        let (kind, rest) = first.trim_start().strip_prefix("[!")?.split_once(']')?;
+        let callout = CALLOUTS.iter().find(|(marker, ..)| marker.eq_ignore_ascii_case(kind))?;
+        Some((callout, rest.trim_start().to_string()))
+    });
+
+    let bar = match callout {
+        Some((&(_, label, icon, color), rest)) => {
+            // The marker's line becomes the title, keeping any text after it
+            lines.remove(0);
+            if !rest.is_empty() {
+                lines.insert(0, format!("  {}", rest));
+            }
+            lines.insert(0, format!("  {}", format!("{} {}", icon, label).color(color).bold()));
+            "┃".color(color).to_string()
+        }
+        None => "│".dimmed().to_string(),
+    };
+
+    lines
+        .iter()
+        .map(|line| match line.strip_prefix("  ") {
+            Some(text) => format!("  {} {}", bar, text),
+            None if line.is_empty() => format!("  {}", bar),
+            None => format!("  {} {}", bar, line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Code block tags for a terminal session, where commands after a `$` or `#`
+/// prompt are mixed with their output.
+const CONSOLE_LANGUAGES: [&str; 2] = ["console", "shell-session"];
+
+/// Optional rendering behaviour, off unless configured.
+#[derive(Debug, Clone, Default)]
+struct RenderOptions {
+    /// Turn `:tada:` style shortcodes in prose into emoji
+    emoji_shortcodes: bool,
+    /// Show `H~2~O` and `x^2^` in prose with Unicode subscripts and
+    /// superscripts
+    unicode_scripts: bool,
+    /// Make URLs clickable with OSC 8 escape sequences
+    hyperlinks: bool,
+    /// Fence code that a reply left unfenced, so it is highlighted
+    fence_code: bool,
+    /// Leave prose unwrapped, keeping the reply's own line breaks
+    no_wrap: bool,
+}
+
+struct MarkdownRenderer {
+    syntax_set: SyntaxSet,
+    theme_set: ThemeSet,
+    wrap_options: Options<'static>,
+    options: RenderOptions,
+    // Table state
+    in_table: bool,
+    table_headers: Vec<String>,
+    current_row: Vec<String>,
+    table_rows: Vec<Vec<String>>,
+    table_alignments: Vec<Option<Alignment>>,
+}
+
+impl MarkdownRenderer {
+    fn new(width: usize) -> Self {
+        let wrap_options = Options::new(width)
+            .initial_indent("  ")
+            .subsequent_indent("  ");
+            
+        Self {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme_set: ThemeSet::load_defaults(),
+            wrap_options,
+            options: RenderOptions::default(),
+            in_table: false,
+            table_headers: Vec::new(),
+            current_row: Vec::new(),
+            table_rows: Vec::new(),
+            table_alignments: Vec::new(),
+        }
+    }
+
+    fn with_options(mut self, options: RenderOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Wraps text rendered from now on to `width` columns.
+    fn set_width(&mut self, width: usize) {
+        self.wrap_options.width = width;
+    }
+
+    /// The usual file extension for `language`, e.g. "py" for "python".
+    fn extension_for(&self, language: &str) -> Option<&str> {
+        self.syntax_set
+            .find_syntax_by_token(language)
+            .and_then(|syntax| syntax.file_extensions.first())
+            .map(String::as_str)
+    }
+
+    /// `text` as a link to `url` if hyperlinks are on, otherwise as it is.
+    fn link(&self, url: &str, text: &str) -> String {
+        if self.options.hyperlinks {
+            format!("{}{}{}", hyperlink_start(url), text, HYPERLINK_END)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// One line of a terminal session. A command after a `$` or `#` prompt
+    /// is highlighted as shell, and anything else is output, drawn dim.
+    fn console_line(&self, line: &str, theme: &Theme) -> String {
+        let (text, ending) = match line.strip_suffix('\n') {
+            Some(text) => (text, "\n"),
+            None => (line, ""),
+        };
+        let prompt_len = match text.as_bytes() {
+            [b'$' | b'#'] => 1,
+            [b'$' | b'#', b' ', ..] => 2,
+            _ => return format!("{}{}", text.dimmed(), ending),
+        };
+        let (prompt, command) = text.split_at(prompt_len);
+        let syntax = self
+            .syntax_set
+            .find_syntax_by_token("bash")
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let command = match HighlightLines::new(syntax, theme).highlight_line(command, &self.syntax_set) {
+            // Reset so the colour doesn't carry on into the output below
+            Ok(ranges) => format!("{}\x1b[0m", as_24_bit_terminal_escaped(&ranges[..], false)),
+            Err(_) => command.to_string(),
+        };
+        format!("{}{}{}", prompt.bold(), command, ending)
+    }
+
+    /// Whether code blocks tagged `language` can be highlighted.
+    fn knows_language(&self, language: &str) -> bool {
+        self.syntax_set.find_syntax_by_token(language).is_some()
+            || self.syntax_set.find_syntax_by_extension(language).is_some()
+    }
+
+    fn render_table(&self) -> String {
+        if self.table_headers.is_empty() && self.table_rows.is_empty() {
+            return String::new();
+        }
+
+        // Create table with headers and alignments
+        let headers: Vec<(String, Option<Alignment>)> = self.table_headers.iter().cloned()
+            .zip(self.table_alignments.iter().cloned())
+            .map(|(header, alignment)| (header.trim().to_string(), alignment))
+            .collect();
+
+        let mut table = Table::new(headers);
 
         // Add rows with proper trimming
         for row in &self.table_rows {
@@ -503,12 +2835,8 @@ impl MarkdownRenderer {
             table.add_row(cleaned_row);
         }
 
-        // Calculate column widths based on terminal size
-        let terminal_width = match terminal_size::terminal_size() {
-            Some((terminal_size::Width(w), _)) => w as usize - 4,
-            None => 76,
-        };
-        table.calculate_column_widths(terminal_width);
+        // Tables get a little less room than wrapped text
+        table.calculate_column_widths(self.wrap_options.width.saturating_sub(2));
 
         table.render()
     }
@@ -524,7 +2852,7 @@ impl MarkdownRenderer {
         }
     }
 
-    fn parse_markdown_table(text: &str) -> Option<(Vec<String>, Vec<Option<Alignment>>, Vec<Vec<String>>)> {
+    fn parse_markdown_table(text: &str) -> Option<ParsedTable> {
         // Split into lines and clean up
         let lines: Vec<_> = text.lines()
             .map(|l| l.trim())
@@ -606,12 +2934,38 @@ impl MarkdownRenderer {
     fn preprocess_table_text(text: &str) -> String {
         let mut result = String::new();
         let mut in_table = false;
-        let mut table_lines = Vec::new();
+        let mut table_lines: Vec<String> = Vec::new();
         let mut column_count = 0;
+        // Indentation of the opening fence while inside a code block
+        let mut fence_indent = None;
 
         for line in text.lines() {
             let trimmed = line.trim();
-            
+
+            // Code is passed through as it is, so its indentation survives
+            // and lines with pipes in them aren't taken for a table
+            let is_fence = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+            if is_fence || fence_indent.is_some() {
+                if in_table {
+                    in_table = false;
+                    for table_line in &table_lines {
+                        result.push_str(table_line);
+                        result.push('\n');
+                    }
+                }
+                let indent = line.len() - line.trim_start().len();
+                match fence_indent {
+                    Some(_) if is_fence => fence_indent = None,
+                    Some(fence) => result.push_str(&line[indent.min(fence)..]),
+                    None => fence_indent = Some(indent),
+                }
+                if is_fence {
+                    result.push_str(trimmed);
+                }
+                result.push('\n');
+                continue;
+            }
+
             if trimmed.contains('|') {
                 // Count columns in the first table line to establish expected width
                 if !in_table {
@@ -666,8 +3020,14 @@ impl MarkdownRenderer {
     }
 
     fn render(&self, text: &str) -> String {
+        self.render_with_hint(text, None)
+    }
+
+    fn render_with_hint(&self, text: &str, language_hint: Option<&str>) -> String {
         // Preprocess text to fix table formatting
-        let processed_text = Self::preprocess_table_text(text);
+        let text = normalize_line_endings(text);
+        let text = if self.options.fence_code { fence_unfenced_code(&text) } else { text };
+        let processed_text = Self::preprocess_table_text(&text);
         
         let theme = &self.theme_set.themes["base16-ocean.dark"];
         let mut output = String::new();
@@ -675,10 +3035,15 @@ impl MarkdownRenderer {
         let mut in_list = false;
         let mut current_paragraph = String::new();
         let mut current_language = String::new();
+        // Where the current image's alt text starts in the paragraph
+        let mut image_alt_start = 0;
+        // Where each open blockquote starts in the output
+        let mut quote_starts = Vec::new();
         let mut renderer = Self {
             syntax_set: SyntaxSet::load_defaults_newlines(),
             theme_set: ThemeSet::load_defaults(),
             wrap_options: self.wrap_options.clone(),
+            options: self.options.clone(),
             in_table: false,
             table_headers: Vec::new(),
             current_row: Vec::new(),
@@ -688,16 +3053,12 @@ impl MarkdownRenderer {
 
         // Try to parse as a table first
         if let Some((headers, alignments, rows)) = Self::parse_markdown_table(&processed_text) {
-            let mut table = Table::new(headers.into_iter().zip(alignments.into_iter()).collect());
+            let mut table = Table::new(headers.into_iter().zip(alignments).collect());
             for row in rows {
                 table.add_row(row);
             }
             
-            let terminal_width = match terminal_size::terminal_size() {
-                Some((terminal_size::Width(w), _)) => w as usize - 4,
-                None => 76,
-            };
-            table.calculate_column_widths(terminal_width);
+            table.calculate_column_widths(self.wrap_options.width.saturating_sub(2));
             return table.render();
         }
 
@@ -724,27 +3085,23 @@ impl MarkdownRenderer {
                 Event::Start(Tag::TableRow) => {
                     renderer.current_row.clear();
                 }
-                Event::End(Tag::TableRow) => {
-                    if !renderer.current_row.is_empty() {
-                        renderer.table_rows.push(renderer.current_row.clone());
-                        renderer.current_row.clear();
-                    }
+                Event::End(Tag::TableRow) if !renderer.current_row.is_empty() => {
+                    renderer.table_rows.push(renderer.current_row.clone());
+                    renderer.current_row.clear();
                 }
                 Event::Start(Tag::TableCell) => {
                     current_paragraph.clear();
                 }
-                Event::End(Tag::TableCell) => {
-                    if renderer.in_table {
-                        renderer.current_row.push(current_paragraph.clone());
-                        current_paragraph.clear();
-                    }
+                Event::End(Tag::TableCell) if renderer.in_table => {
+                    renderer.current_row.push(current_paragraph.clone());
+                    current_paragraph.clear();
                 }
                 Event::Start(Tag::CodeBlock(kind)) => {
                     renderer.flush_paragraph(&mut output, &mut current_paragraph);
                     in_code_block = true;
                     current_language = match kind {
-                        CodeBlockKind::Fenced(lang) => lang.to_string(),
-                        _ => "txt".to_string(),
+                        CodeBlockKind::Fenced(lang) if !lang.is_empty() => lang.to_string(),
+                        _ => language_hint.unwrap_or("txt").to_string(),
                     };
                     output.push('\n');
                 }
@@ -768,10 +3125,8 @@ impl MarkdownRenderer {
                 Event::End(Tag::Item) => {
                     renderer.flush_paragraph(&mut output, &mut current_paragraph);
                 }
-                Event::Start(Tag::Paragraph) => {
-                    if !current_paragraph.is_empty() {
-                        renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    }
+                Event::Start(Tag::Paragraph) if !current_paragraph.is_empty() => {
+                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
                 }
                 Event::End(Tag::Paragraph) => {
                     renderer.flush_paragraph(&mut output, &mut current_paragraph);
@@ -791,19 +3146,57 @@ impl MarkdownRenderer {
                 Event::End(Tag::Strong) => {
                     current_paragraph.push_str("\x1B[22m"); // Reset bold
                 }
+                Event::Start(Tag::BlockQuote) => {
+                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
+                    quote_starts.push(output.len());
+                    // Leave room for the bar drawn down the side
+                    renderer.wrap_options.width = renderer.wrap_options.width.saturating_sub(2);
+                }
+                Event::End(Tag::BlockQuote) => {
+                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
+                    renderer.wrap_options.width += 2;
+                    if let Some(start) = quote_starts.pop() {
+                        let body = output.split_off(start);
+                        output.push_str(&format_quote(&body));
+                        output.push_str("\n\n");
+                    }
+                }
+                // Images can't be shown, so say one is there and where it is
+                Event::Start(Tag::Image(..)) => {
+                    current_paragraph.push_str("🖼 ");
+                    image_alt_start = current_paragraph.len();
+                }
+                Event::End(Tag::Image(_, url, _)) => {
+                    if current_paragraph.len() > image_alt_start {
+                        current_paragraph.push(' ');
+                    }
+                    current_paragraph.push('(');
+                    current_paragraph.push_str(&self.link(&url, &url));
+                    current_paragraph.push(')');
+                }
                 Event::Code(text) => {
                     current_paragraph.push('`');
                     current_paragraph.push_str(&text);
                     current_paragraph.push('`');
                 }
                 Event::Text(text) => {
-                    if in_code_block {
+                    if in_code_block && CONSOLE_LANGUAGES.contains(&current_language.as_str()) {
+                        for line in LinesWithEndings::from(&text) {
+                            output.push_str("    ");
+                            output.push_str(&self.console_line(line, theme));
+                        }
+                    } else if in_code_block {
                         let syntax = if current_language.is_empty() {
-                            self.syntax_set.find_syntax_plain_text()
+                            language_hint
+                                .and_then(|lang| self.syntax_set.find_syntax_by_token(lang))
+                                .or_else(|| language_hint.and_then(|lang| self.syntax_set.find_syntax_by_extension(lang)))
+                                .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text())
                         } else {
                             self.syntax_set
                                 .find_syntax_by_token(&current_language)
                                 .or_else(|| self.syntax_set.find_syntax_by_extension(&current_language))
+                                .or_else(|| language_hint.and_then(|lang| self.syntax_set.find_syntax_by_token(lang)))
+                                .or_else(|| language_hint.and_then(|lang| self.syntax_set.find_syntax_by_extension(lang)))
                                 .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text())
                         };
 
@@ -823,189 +3216,26 @@ impl MarkdownRenderer {
                             }
                         }
                     } else {
-                        current_paragraph.push_str(&text);
-                    }
-                }
-                Event::SoftBreak => {
-                    current_paragraph.push(' ');
-                }
-                Event::HardBreak => {
-                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    output.push('\n');
-                }
-                _ => {}
-            }
-        }
-
-        renderer.flush_table(&mut output);
-        output.trim_end().to_string()
-    }
-
-    fn render_with_hint(&self, text: &str, language_hint: Option<&str>) -> String {
-        // Preprocess text to fix table formatting
-        let processed_text = Self::preprocess_table_text(text);
-        
-        let theme = &self.theme_set.themes["base16-ocean.dark"];
-        let mut output = String::new();
-        let mut in_code_block = false;
-        let mut in_list = false;
-        let mut current_paragraph = String::new();
-        let mut current_language = String::new();
-        let mut renderer = Self {
-            syntax_set: SyntaxSet::load_defaults_newlines(),
-            theme_set: ThemeSet::load_defaults(),
-            wrap_options: self.wrap_options.clone(),
-            in_table: false,
-            table_headers: Vec::new(),
-            current_row: Vec::new(),
-            table_rows: Vec::new(),
-            table_alignments: Vec::new(),
-        };
-
-        // Try to parse as a table first
-        if let Some((headers, alignments, rows)) = Self::parse_markdown_table(&processed_text) {
-            let mut table = Table::new(headers.into_iter().zip(alignments.into_iter()).collect());
-            for row in rows {
-                table.add_row(row);
-            }
-            
-            let terminal_width = match terminal_size::terminal_size() {
-                Some((terminal_size::Width(w), _)) => w as usize - 4,
-                None => 76,
-            };
-            table.calculate_column_widths(terminal_width);
-            return table.render();
-        }
-
-        // If not a table, proceed with normal markdown parsing
-        let parser = Parser::new(&processed_text);
-
-        for event in parser {
-            match event {
-                Event::Start(Tag::Table(alignments)) => {
-                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    renderer.in_table = true;
-                    renderer.table_alignments = alignments.into_iter().map(Some).collect();
-                }
-                Event::End(Tag::Table(_)) => {
-                    renderer.flush_table(&mut output);
-                }
-                Event::Start(Tag::TableHead) => {
-                    renderer.current_row.clear();
-                }
-                Event::End(Tag::TableHead) => {
-                    renderer.table_headers = renderer.current_row.clone();
-                    renderer.current_row.clear();
-                }
-                Event::Start(Tag::TableRow) => {
-                    renderer.current_row.clear();
-                }
-                Event::End(Tag::TableRow) => {
-                    if !renderer.current_row.is_empty() {
-                        renderer.table_rows.push(renderer.current_row.clone());
-                        renderer.current_row.clear();
-                    }
-                }
-                Event::Start(Tag::TableCell) => {
-                    current_paragraph.clear();
-                }
-                Event::End(Tag::TableCell) => {
-                    if renderer.in_table {
-                        renderer.current_row.push(current_paragraph.clone());
-                        current_paragraph.clear();
-                    }
-                }
-                Event::Start(Tag::CodeBlock(kind)) => {
-                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    in_code_block = true;
-                    current_language = match kind {
-                        CodeBlockKind::Fenced(lang) if !lang.is_empty() => lang.to_string(),
-                        _ => language_hint.unwrap_or("txt").to_string(),
-                    };
-                    output.push('\n');
-                }
-                Event::End(Tag::CodeBlock(_)) => {
-                    in_code_block = false;
-                    current_language.clear();
-                    output.push('\n');
-                }
-                Event::Start(Tag::List(_)) => {
-                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    in_list = true;
-                }
-                Event::End(Tag::List(_)) => {
-                    in_list = false;
-                    output.push('\n');
-                }
-                Event::Start(Tag::Item) => {
-                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    current_paragraph.push_str("• ");
-                }
-                Event::End(Tag::Item) => {
-                    renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                }
-                Event::Start(Tag::Paragraph) => {
-                    if !current_paragraph.is_empty() {
-                        renderer.flush_paragraph(&mut output, &mut current_paragraph);
+                        let text = if self.options.unicode_scripts { render_scripts(&text) } else { Cow::Borrowed(&*text) };
+                        if self.options.emoji_shortcodes {
+                            current_paragraph.push_str(&expand_shortcodes(&text));
+                        } else {
+                            current_paragraph.push_str(&text);
+                        }
                     }
                 }
-                Event::End(Tag::Paragraph) => {
+                // Keep a callout marker on a line of its own, so it doesn't
+                // take up room when the text after it is wrapped
+                Event::SoftBreak
+                    if quote_starts.last() == Some(&output.len())
+                        && current_paragraph.starts_with("[!")
+                        && current_paragraph.ends_with(']')
+                        && !current_paragraph.contains(char::is_whitespace) =>
+                {
                     renderer.flush_paragraph(&mut output, &mut current_paragraph);
-                    if !in_list {
-                        output.push('\n');
-                    }
-                }
-                Event::Start(Tag::Emphasis) => {
-                    current_paragraph.push_str("\x1B[3m"); // Italic
-                }
-                Event::End(Tag::Emphasis) => {
-                    current_paragraph.push_str("\x1B[23m"); // Reset italic
-                }
-                Event::Start(Tag::Strong) => {
-                    current_paragraph.push_str("\x1B[1m"); // Bold
-                }
-                Event::End(Tag::Strong) => {
-                    current_paragraph.push_str("\x1B[22m"); // Reset bold
-                }
-                Event::Code(text) => {
-                    current_paragraph.push('`');
-                    current_paragraph.push_str(&text);
-                    current_paragraph.push('`');
                 }
-                Event::Text(text) => {
-                    if in_code_block {
-                        let syntax = if current_language.is_empty() {
-                            language_hint
-                                .and_then(|lang| self.syntax_set.find_syntax_by_token(lang))
-                                .or_else(|| language_hint.and_then(|lang| self.syntax_set.find_syntax_by_extension(lang)))
-                                .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text())
-                        } else {
-                            self.syntax_set
-                                .find_syntax_by_token(&current_language)
-                                .or_else(|| self.syntax_set.find_syntax_by_extension(&current_language))
-                                .or_else(|| language_hint.and_then(|lang| self.syntax_set.find_syntax_by_token(lang)))
-                                .or_else(|| language_hint.and_then(|lang| self.syntax_set.find_syntax_by_extension(lang)))
-                                .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text())
-                        };
-
-                        let mut highlighter = HighlightLines::new(syntax, theme);
-                        
-                        for line in LinesWithEndings::from(&text) {
-                            match highlighter.highlight_line(line, &self.syntax_set) {
-                                Ok(ranges) => {
-                                    output.push_str("    "); // Indent
-                                    let escaped = as_24_bit_terminal_escaped(&ranges[..], false);
-                                    output.push_str(&escaped);
-                                }
-                                Err(_) => {
-                                    output.push_str("    ");
-                                    output.push_str(line);
-                                }
-                            }
-                        }
-                    } else {
-                        current_paragraph.push_str(&text);
-                    }
+                Event::SoftBreak if renderer.options.no_wrap => {
+                    current_paragraph.push('\n');
                 }
                 Event::SoftBreak => {
                     current_paragraph.push(' ');
@@ -1018,10 +3248,20 @@ impl MarkdownRenderer {
             }
         }
 
+        renderer.flush_paragraph(&mut output, &mut current_paragraph);
         renderer.flush_table(&mut output);
         output.trim_end().to_string()
     }
 
+    /// `text` wrapped with `options`, unless wrapping is turned off.
+    fn wrap_lines(&self, text: &str, options: &Options<'_>) -> Vec<String> {
+        if self.options.no_wrap {
+            unwrapped_styled(text, options)
+        } else {
+            wrap_styled(text, options)
+        }
+    }
+
     fn flush_paragraph(&self, output: &mut String, current: &mut String) {
         if !current.is_empty() {
             if current.starts_with('•') {
@@ -1036,7 +3276,7 @@ impl MarkdownRenderer {
                             list_options.subsequent_indent = "    "; // 4 spaces for wrapped lines
 
                             // Wrap each list item separately
-                            for line in wrap(trimmed_item, &list_options) {
+                            for line in self.wrap_lines(trimmed_item, &list_options) {
                                 output.push_str(&line);
                                 output.push('\n');
                             }
@@ -1046,7 +3286,7 @@ impl MarkdownRenderer {
 
             } else {
                 // For normal paragraphs
-                for line in wrap(current, &self.wrap_options) {
+                for line in self.wrap_lines(current, &self.wrap_options) {
                     output.push_str(&line);
                     output.push('\n');
                 }
@@ -1056,160 +3296,3518 @@ impl MarkdownRenderer {
     }
 }
 
-async fn chat_loop(client: MistralClient) -> Result<()> {
-    let mut messages = Vec::new();
-    
-    // Get terminal width, default to 80 if unable to get it
-    let width = match terminal_size::terminal_size() {
-        Some((terminal_size::Width(w), _)) => w as usize - 2, // Subtract 2 for margin
-        None => 80,
-    };
+/// Settings read from `config.toml` in the user's config directory
+/// (e.g. `~/.config/mistral-small/config.toml`), overridden by a project's
+/// `.mistral.toml` if one is found. Every field is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Line endings for printed output: "lf", "crlf" or "native"
+    line_endings: Option<LineEnding>,
+    /// Number of earlier turns sent with each request (see `/window`)
+    window: Option<usize>,
+    /// API root, for proxies or OpenAI-compatible servers
+    base_url: Option<String>,
+    /// Path of the text-completion endpoint used by `--complete`
+    completions_path: Option<String>,
+    /// Model used for chat requests
+    model: Option<String>,
+    /// Largest response body to accept, in bytes
+    max_response_bytes: Option<usize>,
+    /// Stream replies, showing each paragraph as soon as it is complete
+    stream: Option<bool>,
+    /// Payloads that end a streamed reply, besides `[DONE]`
+    stream_done_markers: Option<Vec<String>>,
+    /// Sent as a system message at the start of every request
+    system_prompt: Option<String>,
+    /// JSON file of example user/assistant messages sent after the system
+    /// prompt
+    few_shot: Option<PathBuf>,
+    /// Turn `:tada:` style emoji shortcodes in replies into emoji
+    emoji_shortcodes: Option<bool>,
+    /// Show `H~2~O` and `x^2^` with Unicode subscripts and superscripts
+    unicode_scripts: Option<bool>,
+    /// Fence code that replies leave unfenced (default false)
+    fence_code: Option<bool>,
+    /// Wrap prose to the terminal (default); false keeps the reply's lines
+    wrap: Option<bool>,
+    /// Make URLs clickable; detected from the terminal when unset
+    hyperlinks: Option<bool>,
+    /// Default language for untagged code blocks, e.g. "rust"
+    language: Option<String>,
+    /// Estimated tokens to send at most with each request
+    max_context_tokens: Option<usize>,
+    /// Messages kept in memory (and saved) at most; older turns are dropped
+    max_messages: Option<usize>,
+    /// Regexes for openings to remove from replies, e.g. "Sure, here's"
+    strip_prefixes: Option<Vec<String>>,
+    /// Send demanding prompts to a larger model
+    routing: Option<RouteRules>,
+    /// How the API key is sent: "bearer" (default), "none" or
+    /// `{ header = "x-api-key" }`
+    auth: Option<AuthScheme>,
+    /// Gzip large request bodies; only for servers that accept it
+    compress_requests: Option<bool>,
+    /// Bias for or against token ids, from -100 to 100
+    logit_bias: Option<HashMap<String, f32>>,
+    /// Clear the screen for each reply (default); false keeps earlier
+    /// turns in the terminal's scrollback
+    clear_screen: Option<bool>,
+    /// Keep command history and save conversations (default); false
+    /// writes neither to disk
+    history: Option<bool>,
+    /// Rule printed between turns when the screen isn't cleared
+    separator: SeparatorConfig,
+    /// How errors are displayed
+    errors: ErrorConfig,
+    /// Where to report each turn's usage
+    metrics: MetricsConfig,
+    /// Spotting and handling replies the model declines to give
+    refusals: RefusalConfig,
+    /// Notice shown at startup, and optionally before every prompt
+    banner: BannerConfig,
+}
 
-    let renderer = MarkdownRenderer::new(width);
+/// Name of the per-project config file, looked up like git finds `.git`.
+const PROJECT_CONFIG_FILE: &str = ".mistral.toml";
 
-    // Define command box
-    let command_box = "\
-┌──────────────────────────────────────┐\n\
-│          Available Commands          │\n\
-├──────────────────────────────────────┤\n\
-│    `exit`  - Quit the application    │\n\
-├──────────────────────────────────────┤\n\
-│    `clear` - Clear the screen        │\n\
-├──────────────────────────────────────┤\n\
-│    `new`   - Start a new chat        │\n\
-└──────────────────────────────────────┘";
+impl Config {
+    fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("mistral-small").join("config.toml"))
+    }
 
-    // Function to show command box
-    let show_command_box = || {
-        println!("{}", command_box.green());
-        println!();
-    };
+    /// Finds the nearest project config file in `start` or any of its
+    /// ancestors.
+    fn find_project_config(start: &Path) -> Option<PathBuf> {
+        start
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+            .find(|path| path.is_file())
+    }
 
-    // Show initial welcome message
-    clearscreen::clear()?;
-    if messages.is_empty() {
-        let welcome_message = "I am Mistral Chat AI, a helpful and respectful assistant\npowered by Mistral. Here are some ways I can assist you:\n\n• Provide information and answer questions on a wide\nrange of topics\n• Generate ideas, suggestions, and recommendations\n\nI'm ready to help! How can I assist you today?";
+    /// Loads the global config with the project config for the current
+    /// directory merged over it, or the defaults if there is neither.
+    fn load() -> Result<Self> {
+        let global = Self::path().filter(|path| path.exists());
+        let project = env::current_dir()
+            .ok()
+            .and_then(|dir| Self::find_project_config(&dir));
 
-        print!("{}", renderer.render(&welcome_message).cyan());
-        println!("\n");
-        show_command_box();
-        print!("{}", "> ".blue().bold());
-        io::stdout().flush()?;
-    } else {
-        show_command_box();
-        print!("{}", "> ".blue().bold());
-        io::stdout().flush()?;
+        let mut merged = toml::Table::new();
+        for path in global.iter().chain(project.iter()) {
+            merge_tables(&mut merged, Self::read_table(path)?);
+        }
+        Ok(Self::deserialize(merged)?)
     }
 
-    // Configure rustyline editor with history
-    let mut rl = DefaultEditor::new()?;
-    rl.set_max_history_size(100)?;
-    
-    // Load history from file if it exists
-    let history_file = dirs::home_dir()
-        .map(|mut path| {
-            path.push(".mistral_history");
-            path
-        })
-        .unwrap_or_else(|| ".mistral_history".into());
-    
-    if history_file.exists() {
-        let _ = rl.load_history(&history_file);
-    }
-    
-    loop {
-        let prompt = format!("{}", "> ".blue().bold());
-        match rl.readline(&prompt) {
-            Ok(line) => {
-                let input = line.trim();
-                if input.eq_ignore_ascii_case("exit") {
-                    // Save history before exiting
-                    let _ = rl.save_history(&history_file);
-                    break;
-                } else if input.eq_ignore_ascii_case("clear") {
-                    clearscreen::clear()?;
-                    show_command_box();
-                    continue;
-                } else if input.eq_ignore_ascii_case("new") {
-                    messages.clear();
-                    clearscreen::clear()?;
-                    show_command_box();
-                    println!("{}", "Starting a fresh conversation...".green());
-                    print!("{}", "> ".blue().bold());
-                    io::stdout().flush()?;
-                    continue;
-                }
+    /// Reads one config file, checking it on its own so that errors point
+    /// at the file they came from.
+    fn read_table(path: &Path) -> Result<toml::Table> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {}", path.display(), e))?;
+        let mut table: toml::Table = toml::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("invalid config {}: {}", path.display(), e))?;
+        Self::deserialize(table.clone())
+            .map_err(|e| anyhow::anyhow!("invalid config {}: {}", path.display(), e))?;
 
-                // Add valid input to history
-                if !input.is_empty() {
-                    rl.add_history_entry(input)?;
+        // Relative file paths are relative to the config file, not to
+        // wherever the program happens to be run from
+        if let Some(dir) = path.parent() {
+            for key in CONFIG_PATH_KEYS {
+                if let Some(toml::Value::String(value)) = table.get_mut(key) {
+                    if Path::new(value.as_str()).is_relative() {
+                        *value = dir.join(&*value).to_string_lossy().into_owned();
+                    }
                 }
+            }
+        }
+        Ok(table)
+    }
+}
 
-                messages.push(ChatMessage {
-                    role: "user".to_string(),
-                    content: input.to_string(),
-                });
+/// Config keys that name a file.
+const CONFIG_PATH_KEYS: [&str; 1] = ["few_shot"];
 
-                print!("{}", "Thinking...".yellow());
-                io::stdout().flush()?;
+/// Overlays `overlay` onto `base`. Nested tables are merged key by key,
+/// anything else in `overlay` replaces the value in `base`.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
 
-                match client.send_message(messages.clone()).await {
-                    Ok((response, language_hint)) => {
-                        clearscreen::clear()?;
-                        show_command_box();
-                        
-                        print!("{}", "> ".blue().bold());
-                        println!("{}", input);
-                        println!();
-                        
-                        print!("{}", renderer.render_with_hint(&response, language_hint.as_deref()).cyan());
-                        println!();
-                        println!();
+/// Command-line options.
+#[derive(clap::Parser, Debug)]
+#[command(version, about = "Chat with Mistral AI from the terminal")]
+struct Cli {
+    /// Exit after this many seconds without input
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
 
-                        messages.push(ChatMessage {
-                            role: "assistant".to_string(),
-                            content: response,
-                        });
-                        
-                        print!("{}", "> ".blue().bold());
-                        io::stdout().flush()?;
-                    }
-                    Err(e) => {
-                        print!("\r{}\r", " ".repeat(width)); // Clear "Thinking..." line
-                        println!();
-                        for line in wrap(&format!("Error: {}", e), &renderer.wrap_options) {
-                            println!("{}", line.red());
-                        }
-                        println!();
-                    }
-                }
-            }
-            Err(ReadlineError::Interrupted) => {
-                println!("Use 'exit' to quit");
-                continue;
-            }
-            Err(ReadlineError::Eof) => {
-                break;
-            }
-            Err(err) => {
-                println!("Error: {}", err);
-                break;
-            }
+    /// Render a prompt template, send it once and print the reply
+    #[arg(long, value_name = "FILE")]
+    template: Option<PathBuf>,
+
+    /// Template variable, overriding any environment variable of the same name
+    #[arg(long = "var", value_name = "KEY=VALUE", value_parser = parse_template_var)]
+    vars: Vec<(String, String)>,
+
+    /// Check that the API key and endpoint work, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Read the system prompt from this file instead of the config
+    #[arg(long, value_name = "FILE")]
+    system_file: Option<PathBuf>,
+
+    /// Reload --system-file before a turn whenever it has changed
+    #[arg(long, requires = "system_file")]
+    watch: bool,
+
+    /// Few-shot example messages (JSON) to send ahead of the conversation
+    #[arg(long, value_name = "FILE")]
+    examples: Option<PathBuf>,
+
+    /// Use the full-screen interface (needs the `tui` feature)
+    #[arg(long)]
+    tui: bool,
+
+    /// Send one prompt with each combination of --models and
+    /// --temperatures and compare the replies in a table
+    #[arg(long, value_name = "PROMPT")]
+    sweep: Option<String>,
+
+    /// Models to compare in a sweep (default: the configured model)
+    #[arg(long, value_name = "MODEL,...", value_delimiter = ',', requires = "sweep")]
+    models: Vec<String>,
+
+    /// Temperatures to compare in a sweep (default: the model's own)
+    #[arg(long, value_name = "TEMP,...", value_delimiter = ',', requires = "sweep")]
+    temperatures: Vec<f32>,
+
+    /// Don't read or write command history or save the conversation
+    #[arg(long)]
+    no_history: bool,
+
+    /// Send a prompt to the text-completion endpoint (for servers without
+    /// chat) and print the generated text
+    #[arg(long, value_name = "PROMPT", conflicts_with_all = ["template", "sweep"])]
+    complete: Option<String>,
+
+    /// Resume the most recently saved conversation
+    #[arg(short = 'c', long = "continue", conflicts_with = "no_history")]
+    continue_last: bool,
+}
+
+/// A system prompt kept in a file. When watched, it is read again before
+/// any turn that follows a change to the file.
+struct SystemPromptFile {
+    path: PathBuf,
+    watch: bool,
+    /// Modification time and length when last read
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl SystemPromptFile {
+    /// Reads the prompt at `path`, returning it with the file to watch.
+    fn open(path: &Path, watch: bool) -> Result<(Self, String)> {
+        let mut file = Self {
+            path: path.to_path_buf(),
+            watch,
+            stamp: None,
+        };
+        let prompt = file.read()?;
+        Ok((file, prompt))
+    }
+
+    fn read(&mut self) -> Result<String> {
+        let prompt = std::fs::read_to_string(&self.path)
+            .map_err(|e| anyhow::anyhow!("Could not read system prompt {}: {}", self.path.display(), e))?;
+        self.stamp = Self::stamp(&self.path);
+        Ok(normalize_line_endings(prompt.trim_end()).into_owned())
+    }
+
+    fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// The new prompt if the file is watched and has changed since it was
+    /// last read.
+    fn reload_if_changed(&mut self) -> Result<Option<String>> {
+        if !self.watch || Self::stamp(&self.path) == self.stamp {
+            return Ok(None);
+        }
+        self.read().map(Some)
+    }
+}
+
+/// Reads few-shot examples from a JSON array of `{"role", "content"}`
+/// messages. They must alternate user and assistant, starting with a
+/// question and ending with its answer. YAML is not supported.
+fn load_examples(path: &Path) -> Result<Vec<ChatMessage>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Could not read examples {}: {}", path.display(), e))?;
+    let examples: Vec<ChatMessage> = serde_json::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("Invalid examples file {}: {}", path.display(), e))?;
+    for (i, example) in examples.iter().enumerate() {
+        let expected = if i % 2 == 0 { "user" } else { "assistant" };
+        if example.role != expected {
+            anyhow::bail!(
+                "Example {} in {} has role `{}` where `{}` was expected; examples alternate user and assistant",
+                i + 1,
+                path.display(),
+                example.role,
+                expected
+            );
+        }
+    }
+    if examples.len() % 2 == 1 {
+        anyhow::bail!("{} ends with a user message that has no answer", path.display());
+    }
+    Ok(examples
+        .into_iter()
+        .map(|example| ChatMessage {
+            content: normalize_line_endings(&example.content).into_owned(),
+            ..example
+        })
+        .collect())
+}
+
+fn parse_template_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got `{}`", arg)),
+    }
+}
+
+/// Fills `{{NAME}}` placeholders in a prompt template.
+///
+/// Values come from `vars` first, then from `lookup` (the environment in
+/// practice). `{{NAME|default}}` falls back to `default` when neither has
+/// the variable; any other undefined variable is an error.
+fn render_template(
+    template: &str,
+    vars: &HashMap<String, String>,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+        let Some(end) = after_open.find("}}") else {
+            // Unterminated placeholder, keep the remainder as literal text
+            output.push_str(&rest[start..]);
+            return Ok(output);
+        };
+
+        let placeholder = &after_open[..end];
+        let (name, default) = match placeholder.split_once('|') {
+            Some((name, default)) => (name.trim(), Some(default)),
+            None => (placeholder.trim(), None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("invalid template variable `{{{{{}}}}}`", placeholder);
         }
+
+        let value = vars
+            .get(name)
+            .cloned()
+            .or_else(|| lookup(name))
+            .or_else(|| default.map(str::to_string))
+            .ok_or_else(|| anyhow::anyhow!(
+                "template variable `{}` is not defined (pass --var {}=... or set it in the environment)",
+                name, name
+            ))?;
+        output.push_str(&value);
+        rest = &after_open[end + 2..];
     }
 
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Renders the template given on the command line, sends it as a single
+/// user message and prints the raw reply, for scripted use.
+async fn run_template(
+    session: &mut ChatSession,
+    path: &Path,
+    vars: Vec<(String, String)>,
+    line_ending: LineEnding,
+) -> Result<()> {
+    let template = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read template {}: {}", path.display(), e))?;
+    let vars: HashMap<String, String> = vars.into_iter().collect();
+    let prompt = render_template(&template, &vars, |name| env::var(name).ok())?;
+
+    let (response, _) = session.send(&prompt).await?;
+    print!("{}", line_ending.apply(&format!("{}\n", response)));
+
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv::dotenv().ok();
-    
-    let api_key = env::var("MISTRAL_API_KEY")
-        .expect("MISTRAL_API_KEY must be set in environment variables or .env file");
+/// One request per model and temperature combination, all sending
+/// `messages`. A temperature of `None` leaves the API's default.
+fn sweep_requests(models: &[String], temperatures: &[Option<f32>], messages: &[ChatMessage]) -> Vec<BatchRequest> {
+    models
+        .iter()
+        .flat_map(|model| {
+            temperatures.iter().map(|&temperature| BatchRequest {
+                model: model.clone(),
+                params: Params {
+                    temperature,
+                    ..Params::default()
+                },
+                messages: messages.to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// The temperature of a sweep request as shown to the user.
+fn sweep_temperature(request: &BatchRequest) -> String {
+    request
+        .params
+        .temperature
+        .map_or("default".to_string(), |t| t.to_string())
+}
+
+/// Columns of a reply's first line shown in the sweep table.
+const SWEEP_REPLY_PREVIEW: usize = 40;
+
+/// The timing, token usage and start of the reply of each sweep request,
+/// one row per request. The replies themselves are printed above it, in
+/// full.
+fn sweep_table(requests: &[BatchRequest], replies: &[Result<BatchReply>]) -> Table {
+    let mut table = Table::new(vec![
+        ("Model".to_string(), None),
+        ("Temp".to_string(), Some(Alignment::Right)),
+        ("Time".to_string(), Some(Alignment::Right)),
+        ("Tokens in/out".to_string(), Some(Alignment::Right)),
+        ("Reply".to_string(), None),
+    ]);
+    for (request, reply) in requests.iter().zip(replies) {
+        let (time, tokens, preview) = match reply {
+            Ok(reply) => (
+                format!("{:.2}s", reply.elapsed.as_secs_f64()),
+                reply
+                    .usage
+                    .as_ref()
+                    .map_or("-".to_string(), |usage| format!("{}/{}", usage.prompt_tokens, usage.completion_tokens)),
+                reply_preview(&reply.content),
+            ),
+            Err(_) => ("failed".to_string(), "-".to_string(), "-".to_string()),
+        };
+        table.add_row(vec![request.model.clone(), sweep_temperature(request), time, tokens, preview]);
+    }
+    table
+}
+
+/// The first non-blank line of `content`, cut to `SWEEP_REPLY_PREVIEW`
+/// columns, with " …" when there is more.
+fn reply_preview(content: &str) -> String {
+    let first_line = content.lines().find(|line| !line.trim().is_empty()).unwrap_or_default();
+    let summary = textwrap::wrap(first_line.trim(), SWEEP_REPLY_PREVIEW)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let more = summary.len() < content.trim().len();
+    format!("{}{}", summary, if more { " …" } else { "" })
+}
 
-    let client = MistralClient::new(api_key);
-    chat_loop(client).await?;
+/// Sends `prompt` once for every model and temperature combination and
+/// prints each reply under a heading naming its model and temperature,
+/// followed by a table comparing timing, token usage and how each reply
+/// starts.
+async fn run_sweep(
+    session: &mut ChatSession,
+    config: &Config,
+    prompt: &str,
+    models: &[String],
+    temperatures: &[f32],
+) -> Result<()> {
+    let models = if models.is_empty() {
+        vec![session.client.model.clone()]
+    } else {
+        models.to_vec()
+    };
+    let temperatures: Vec<Option<f32>> = if temperatures.is_empty() {
+        vec![None]
+    } else {
+        temperatures.iter().copied().map(Some).collect()
+    };
+
+    session.messages.push(ChatMessage::now("user", prompt.to_string()));
+    let requests = sweep_requests(&models, &temperatures, &session.request_messages());
+
+    println!("{}", format!("Sending {} request(s)...", requests.len()).yellow());
+    let replies = session.client.send_batch(&requests).await;
+
+    let width = terminal_width();
+    let renderer = build_renderer(config, width);
+    let errors = ErrorFormatter::new(&config.errors, width);
+    for (request, reply) in requests.iter().zip(&replies) {
+        println!();
+        println!(
+            "{}",
+            format!("{} at temperature {}", request.model, sweep_temperature(request)).bold()
+        );
+        match reply {
+            Ok(reply) => print!("{}", renderer.render(&reply.content).cyan()),
+            Err(e) => print_error(e, &errors),
+        }
+    }
 
+    let mut table = sweep_table(&requests, &replies);
+    table.calculate_column_widths(width);
+    println!();
+    print!("{}", table.render());
     Ok(())
 }
+
+/// Where `read_input` gets lines from: the line editor, or a scripted
+/// source in tests. Reading blocks, so it happens on its own thread.
+trait LineSource: Send + 'static {
+    fn read_line(&mut self, prompt: &str) -> rustyline::Result<String>;
+}
+
+impl LineSource for DefaultEditor {
+    fn read_line(&mut self, prompt: &str) -> rustyline::Result<String> {
+        self.readline(prompt)
+    }
+}
+
+/// What happened while waiting for the user to enter a line.
+enum InputEvent {
+    Line(rustyline::Result<String>),
+    IdleTimeout,
+    Shutdown,
+}
+
+/// Whether `answer` to a `[y/N]` question is a yes. Anything else, including
+/// Ctrl-C, Ctrl-D or an abandoned wait, counts as no.
+fn answered_yes(answer: &InputEvent) -> bool {
+    matches!(
+        answer,
+        InputEvent::Line(Ok(line)) if matches!(line.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+    )
+}
+
+/// Reads a line on a blocking thread and races it against the idle timeout
+/// and termination signals, so the REPL is not stuck inside `readline`.
+///
+/// The editor is handed back once a line arrives. If the wait is cancelled
+/// the editor stays with the abandoned thread, so callers should exit.
+async fn read_input<S: LineSource>(
+    editor: &mut Option<S>,
+    prompt: &str,
+    idle_timeout: Option<Duration>,
+) -> Result<InputEvent> {
+    let mut rl = editor
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("line editor is no longer available"))?;
+    let prompt = prompt.to_string();
+    let mut task = tokio::task::spawn_blocking(move || {
+        let line = rl.read_line(&prompt);
+        (rl, line)
+    });
+
+    let idle = async {
+        match idle_timeout {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        joined = &mut task => {
+            let (rl, line) = joined?;
+            *editor = Some(rl);
+            Ok(InputEvent::Line(line))
+        }
+        _ = idle => Ok(InputEvent::IdleTimeout),
+        _ = shutdown_signal() => Ok(InputEvent::Shutdown),
+    }
+}
+
+/// Resolves when the process is asked to terminate. Ctrl-C is left to
+/// rustyline, which reports it as `ReadlineError::Interrupted`.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut term), Ok(mut hangup)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::hangup()),
+    ) else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = hangup.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    std::future::pending::<()>().await
+}
+
+/// Snapshot of the terminal settings taken before `readline` switches the
+/// terminal to raw mode, used to put it back if we abandon the read.
+#[cfg(unix)]
+struct TerminalMode(Option<libc::termios>);
+
+#[cfg(unix)]
+impl TerminalMode {
+    fn capture() -> Self {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr only writes into the provided termios struct.
+        let ok = unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } == 0;
+        // SAFETY: the struct is initialised whenever tcgetattr succeeds.
+        Self(ok.then(|| unsafe { termios.assume_init() }))
+    }
+
+    fn restore(&self) {
+        if let Some(termios) = &self.0 {
+            // SAFETY: restores settings previously read by tcgetattr.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct TerminalMode;
+
+#[cfg(not(unix))]
+impl TerminalMode {
+    fn capture() -> Self {
+        Self
+    }
+
+    fn restore(&self) {}
+}
+
+/// Exits after a wait for input was cut short by the idle timeout or a
+/// signal. The abandoned readline thread still owns the terminal, so the
+/// settings are restored here instead of waiting on that thread.
+fn exit_abandoned_input(terminal_mode: &TerminalMode, event: &InputEvent) -> ! {
+    terminal_mode.restore();
+    println!();
+    if matches!(event, InputEvent::IdleTimeout) {
+        println!("{}", "Exiting after being idle.".yellow());
+    }
+    std::process::exit(0);
+}
+
+/// Throws away anything typed while a reply was being generated, so it
+/// can't be sent as the next message by accident. Returns whether there
+/// was anything to discard.
+#[cfg(unix)]
+fn discard_typeahead() -> bool {
+    discard_pending_input(libc::STDIN_FILENO)
+}
+
+/// `discard_typeahead` for the terminal open as `fd`. Anything that isn't
+/// a terminal is left alone.
+#[cfg(unix)]
+fn discard_pending_input(fd: libc::c_int) -> bool {
+    // SAFETY: isatty only inspects the descriptor.
+    if unsafe { libc::isatty(fd) } != 1 {
+        return false;
+    }
+    let mut pending: libc::c_int = 0;
+    // SAFETY: FIONREAD writes the number of unread bytes into `pending`.
+    let ok = unsafe { libc::ioctl(fd, libc::FIONREAD, &mut pending) } == 0;
+    if !ok || pending == 0 {
+        return false;
+    }
+    // SAFETY: tcflush only drops the terminal's queued input.
+    unsafe { libc::tcflush(fd, libc::TCIFLUSH) == 0 }
+}
+
+#[cfg(not(unix))]
+fn discard_typeahead() -> bool {
+    false
+}
+
+/// Shown when input typed during a request was discarded, so it doesn't
+/// vanish without a word.
+const TYPEAHEAD_NOTICE: &str = "Ignored input typed while waiting for the reply";
+
+/// Runs a connectivity check and prints the outcome. Returns whether it
+/// succeeded.
+async fn check_connection(client: &MistralClient) -> bool {
+    println!("{}", format!("Checking {} ...", client.base_url).yellow());
+    match client.ping().await {
+        Ok(report) => {
+            println!(
+                "{}",
+                format!("✓ Connected and authenticated in {} ms", report.latency.as_millis()).green()
+            );
+            if report.model_listed {
+                println!("{}", format!("  Model: {}", client.model).green());
+            } else {
+                println!(
+                    "{}",
+                    format!("  Model: {} (not in the server's model list, it may be an alias)", client.model).yellow()
+                );
+            }
+            true
+        }
+        Err(e) => {
+            println!("{}", format!("✗ Check failed: {}", e).red());
+            let diagnosis = match e.downcast_ref::<ApiError>() {
+                Some(api_error) => api_error.diagnosis(),
+                None => "The server's response could not be understood. Check that base_url points at a Mistral-compatible API.",
+            };
+            println!("{}", format!("  {}", diagnosis).red());
+            false
+        }
+    }
+}
+
+/// Narrowest and widest rendering width `/width` accepts.
+const WIDTH_RANGE: std::ops::RangeInclusive<usize> = 20..=500;
+
+/// Columns available for replies: the terminal's width less a margin, or
+/// 80 if it can't be found.
+fn terminal_width() -> usize {
+    match terminal_size::terminal_size() {
+        Some((terminal_size::Width(w), _)) => (w as usize).saturating_sub(2),
+        None => 80,
+    }
+}
+
+/// The API key to send: `from_env` (`MISTRAL_API_KEY`) if set. A local
+/// server or gateway that takes no credentials doesn't need a key, but the
+/// Mistral API always does, so `auth = "none"` only counts with a
+/// `base_url` pointing somewhere else.
+fn resolve_api_key(from_env: Option<String>, config: &Config) -> Result<String> {
+    let custom_server = config
+        .base_url
+        .as_deref()
+        .is_some_and(|url| url.trim_end_matches('/') != DEFAULT_BASE_URL);
+    match from_env {
+        Some(key) => Ok(key),
+        None if config.auth == Some(AuthScheme::None) && custom_server => Ok(String::new()),
+        None if config.auth == Some(AuthScheme::None) => anyhow::bail!(
+            "MISTRAL_API_KEY must be set for the Mistral API; auth = \"none\" only \
+             applies when base_url points at a server that needs no key"
+        ),
+        None => anyhow::bail!(
+            "MISTRAL_API_KEY must be set in environment variables or .env file \
+             (or set auth = \"none\" in the config for a server that needs no key)"
+        ),
+    }
+}
+
+/// A renderer for `width` columns with the rendering options from `config`.
+fn build_renderer(config: &Config, width: usize) -> MarkdownRenderer {
+    MarkdownRenderer::new(width).with_options(RenderOptions {
+        emoji_shortcodes: config.emoji_shortcodes.unwrap_or(false),
+        unicode_scripts: config.unicode_scripts.unwrap_or(false),
+        hyperlinks: config.hyperlinks.unwrap_or_else(terminal_supports_hyperlinks),
+        fence_code: config.fence_code.unwrap_or(false),
+        no_wrap: !config.wrap.unwrap_or(true),
+    })
+}
+
+/// Lists the conversation one message per line, numbered for `/continue`,
+/// with each message cut down to its first line.
+fn print_history(session: &ChatSession, width: usize) {
+    if session.messages.is_empty() {
+        println!("{}", "The conversation is empty".green());
+        return;
+    }
+    // Older saved conversations have no times, so only make room for them
+    // when there are some
+    let timed = session.messages.iter().any(|msg| msg.created_at.is_some());
+    for (i, msg) in session.messages.iter().enumerate() {
+        let time = match msg.created_at {
+            Some(created_at) => format!("{} ", created_at.format("%H:%M")),
+            None if timed => "      ".to_string(),
+            None => String::new(),
+        };
+        let label = format!("  {:>3}. {}{:<9} ", i + 1, time, msg.role);
+        let first_line = msg.content.lines().find(|line| !line.trim().is_empty()).unwrap_or_default();
+        let room = width.saturating_sub(label.len()).max(10);
+        let summary = textwrap::wrap(first_line.trim(), room)
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let more = summary.len() < msg.content.trim().len();
+        let line = format!("{}{}{}", label, summary, if more { " …" } else { "" });
+        if msg.role == "assistant" {
+            println!("{}", line.cyan());
+        } else {
+            println!("{}", line.green());
+        }
+    }
+    println!();
+}
+
+/// Prints the size of the conversation and of the context sent with each
+/// request, with token counts estimated locally.
+fn print_stats(session: &ChatSession) {
+    let turns = session.messages.iter().filter(|msg| msg.role == "user").count();
+    let total = MistralClient::estimate_tokens(&session.messages);
+    let budget = session
+        .max_context_tokens
+        .map_or(String::new(), |budget| format!(" (budget {})", budget));
+    let sent = match session.last_request {
+        Some((messages, tokens)) => format!("{} messages, ~{} tokens{}", messages, tokens, budget),
+        None => format!("nothing yet{}", budget),
+    };
+    let lines = [
+        format!("Messages: {} ({} turns)", session.messages.len(), turns),
+        format!("Conversation: ~{} tokens", total),
+        format!("Sent with the last message: {}", sent),
+        format!("Model: {}", session.client.model),
+    ];
+    for line in lines {
+        println!("{}", format!("  {}", line).green());
+    }
+    println!("{}", "  Token counts are estimates".dimmed());
+    println!();
+}
+
+/// Explains that older turns were left out after the server found the
+/// conversation too long.
+fn context_trimmed_notice(session: &ChatSession) -> Option<String> {
+    let budget = session.max_context_tokens.filter(|_| session.context_trimmed)?;
+    Some(format!(
+        "The conversation was too long for the model, so older turns are now left out of each request (about {} tokens are sent)",
+        budget
+    ))
+}
+
+fn print_context_trimmed(session: &ChatSession) {
+    if let Some(notice) = context_trimmed_notice(session) {
+        println!("{}", notice.yellow());
+        println!();
+    }
+}
+
+/// Says which model a prompt went to, when auto-routing is on.
+fn print_route(client: &MistralClient, prompt: &str) {
+    if client.route.is_none() {
+        return;
+    }
+    let note = match client.route(prompt) {
+        (model, Some(reason)) => format!("Using {} ({})", model, reason),
+        (model, None) => format!("Using {}", model),
+    };
+    println!("{}", note.dimmed());
+    println!();
+}
+
+/// Chat commands listed by `/help`.
+const COMMAND_HELP: &str = "\
+Chat commands:
+  /help                  Show this list
+  /ping                  Check the connection, API key and model
+  /sessions              List saved conversations
+  /load <name>           Resume a saved conversation
+  /fork <name>           Save a copy under a new name and carry on in it
+  /retry                 Ask again for a new version of the last reply
+  /regenerate [temp]     Like /retry, optionally with a different temperature
+  /window [k|off]        Send only the last k turns with each request
+  /lang [name|off]       Highlight untagged code blocks as this language
+  /width [n|auto]        Render replies n columns wide, or fit the terminal
+  /wrap [on|off]         Wrap prose to fit, or keep the reply's own lines
+  /model [name]          Show or change the model (turns off auto-routing)
+  /stats                 Show the conversation's size in messages and tokens
+  /history               List the messages so far, numbered
+  /continue <n>          Rewind to reply n and carry on from there
+  /source <n>            Print reply n as raw markdown
+  /writecode <n> <path>  Save code block n of the last reply to a file
+  /pipe <n> <command>    Run a shell command with code block n as its input
+  /ran <n> <status>      Tell the model what happened when you ran code block n";
+
+/// The `index`th code block (1-based) of `reply`.
+fn code_block(reply: &str, index: usize) -> Result<CodeBlock> {
+    let mut blocks = extract_code_blocks(reply);
+    let count = blocks.len();
+    match index.checked_sub(1).filter(|&i| i < count) {
+        Some(i) => Ok(blocks.swap_remove(i)),
+        None if count == 0 => anyhow::bail!("The last reply has no code blocks"),
+        None => anyhow::bail!("There is no code block {}; the last reply has {}", index, count),
+    }
+}
+
+/// A fence of backticks long enough to wrap `text` in a code block, even
+/// if it contains fences of its own.
+fn fence_for(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Statuses longer than this are sent as a block of output rather than
+/// inline.
+const RAN_INLINE_STATUS: usize = 60;
+
+/// The message `/ran` sends: code block `index` of the last reply, with what
+/// happened when the user ran it, so the model can carry on from there.
+fn ran_follow_up(block: &CodeBlock, index: usize, status: &str) -> String {
+    let code = block.code.trim_end();
+    let fence = fence_for(code);
+    let mut message = format!(
+        "I ran code block {}:\n\n{}{}\n{}\n{}\n\n",
+        index,
+        fence,
+        block.language.as_deref().unwrap_or_default(),
+        code,
+        fence
+    );
+    let status = status.trim();
+    if status.contains('\n') || status.chars().count() > RAN_INLINE_STATUS {
+        let fence = fence_for(status);
+        message.push_str(&format!("and got:\n\n{}\n{}\n{}", fence, status, fence));
+    } else {
+        message.push_str(&format!("and got: {}", status));
+    }
+    message
+}
+
+/// Writes the `index`th code block (1-based) of `reply` to `path`. A path
+/// without an extension gets one from the block's language. Returns the
+/// path written.
+fn write_code_block(reply: &str, index: usize, path: &Path, renderer: &MarkdownRenderer) -> Result<PathBuf> {
+    let block = code_block(reply, index)?;
+
+    let mut path = path.to_path_buf();
+    if path.extension().is_none() {
+        if let Some(extension) = block.language.as_deref().and_then(|lang| renderer.extension_for(lang)) {
+            path.set_extension(extension);
+        }
+    }
+    std::fs::write(&path, &block.code)
+        .map_err(|e| anyhow::anyhow!("Could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Writes a reply for `/source` exactly as stored, byte for byte, for
+/// copying: no rendering and no line-ending conversion.
+fn write_source(out: &mut impl Write, reply: &ChatMessage) -> io::Result<()> {
+    out.write_all(reply.content.as_bytes())?;
+    out.flush()
+}
+
+/// Runs `command` in the shell with `code` on its stdin, returning what it
+/// printed and its exit status.
+async fn pipe_code(code: &str, command: &str) -> Result<std::process::Output> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = tokio::process::Command::new(shell)
+        .args([flag, command])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Could not run `{}`: {}", command, e))?;
+
+    // Feed stdin alongside reading the output, so a command that prints a
+    // lot before reading everything can't deadlock
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let code = code.to_string();
+    let writer = tokio::spawn(async move {
+        use tokio::io::AsyncWriteExt;
+        // A command that exits without reading all of it is fine
+        let _ = stdin.write_all(code.as_bytes()).await;
+    });
+    let output = child.wait_with_output().await?;
+    let _ = writer.await;
+    Ok(output)
+}
+
+/// Saves the conversation after a turn. A failure is only a warning, since
+/// the reply itself was received fine.
+fn save_session(session: &ChatSession) {
+    if let Err(e) = session.save() {
+        println!("{}", format!("Warning: could not save the conversation: {}", e).yellow());
+    }
+}
+
+/// How errors are printed in the chat.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ErrorStyle {
+    /// A bordered box with the kind of error, the message and a hint
+    #[default]
+    Box,
+    /// A single wrapped `Error: ...` line, with the hint on the next line
+    Line,
+}
+
+/// `[errors]` section of the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ErrorConfig {
+    style: Option<ErrorStyle>,
+    /// Any colour name understood by `colored`, e.g. "red" or "bright magenta"
+    color: Option<String>,
+}
+
+/// `[separator]` section of the config file: the rule printed between
+/// turns when `clear_screen` is off.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SeparatorConfig {
+    /// On unless set to false
+    enabled: Option<bool>,
+    /// Show the number of the turn that follows (default true)
+    number: Option<bool>,
+    /// Show the time (default false)
+    timestamp: Option<bool>,
+}
+
+impl SeparatorConfig {
+    /// A dim rule `width` columns wide, labelled with the turn number and
+    /// time if configured. `None` when separators are turned off.
+    fn rule(&self, width: usize, turn: usize) -> Option<String> {
+        if !self.enabled.unwrap_or(true) {
+            return None;
+        }
+        let mut label = Vec::new();
+        if self.number.unwrap_or(true) {
+            label.push(format!("#{}", turn));
+        }
+        if self.timestamp.unwrap_or(false) {
+            label.push(chrono::Local::now().format("%H:%M").to_string());
+        }
+
+        let mut rule = "──".to_string();
+        if !label.is_empty() {
+            rule.push_str(&format!(" {} ", label.join(" · ")));
+        }
+        let remaining = width.saturating_sub(textwrap::core::display_width(&rule));
+        rule.push_str(&"─".repeat(remaining));
+        Some(rule.dimmed().to_string())
+    }
+
+    /// The rule shown between the last exchange of `session` and the next
+    /// prompt, numbered for the turn about to start.
+    fn before_next_turn(&self, session: &ChatSession, width: usize) -> Option<String> {
+        let turn = session.messages.iter().filter(|msg| msg.role == "user").count() + 1;
+        self.rule(width, turn)
+    }
+}
+
+/// When the `[banner]` is shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum BannerFrequency {
+    /// At startup only
+    #[default]
+    Once,
+    /// At startup and before every prompt
+    EveryTurn,
+}
+
+/// `[banner]` section of the config file: a notice such as "Internal use
+/// only" shown at startup, separate from the welcome message.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BannerConfig {
+    /// Markdown, rendered like a reply
+    text: Option<String>,
+    /// Any colour name understood by `colored` (default yellow)
+    color: Option<String>,
+    frequency: Option<BannerFrequency>,
+}
+
+impl BannerConfig {
+    /// The banner rendered and coloured, without a trailing newline.
+    /// `None` when no text is configured.
+    fn render(&self, renderer: &MarkdownRenderer) -> Option<String> {
+        let text = self.text.as_deref().filter(|text| !text.trim().is_empty())?;
+        let color = self
+            .color
+            .as_deref()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(Color::Yellow);
+        Some(renderer.render(text).trim_end().color(color).to_string())
+    }
+
+    /// Whether the banner is shown again before every prompt.
+    fn every_turn(&self) -> bool {
+        self.frequency.unwrap_or_default() == BannerFrequency::EveryTurn
+    }
+}
+
+/// Formats errors for display, splitting API errors into their kind, the
+/// server's message and a hint for fixing them.
+struct ErrorFormatter {
+    style: ErrorStyle,
+    color: Color,
+    width: usize,
+}
+
+impl ErrorFormatter {
+    fn new(config: &ErrorConfig, width: usize) -> Self {
+        let color = config
+            .color
+            .as_deref()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(Color::Red);
+        Self {
+            style: config.style.unwrap_or_default(),
+            color,
+            width,
+        }
+    }
+
+    /// The kind, message and optional hint for an error.
+    fn parts(error: &anyhow::Error) -> (&'static str, String, Option<&'static str>) {
+        match error.downcast_ref::<ApiError>() {
+            Some(api_error) => (api_error.kind(), api_error.to_string(), Some(api_error.diagnosis())),
+            None => ("Error", error.to_string(), None),
+        }
+    }
+
+    /// Formats `error` as coloured text, without a trailing newline.
+    fn format(&self, error: &anyhow::Error) -> String {
+        let (kind, message, hint) = Self::parts(error);
+        let text = match self.style {
+            ErrorStyle::Line => {
+                let options = Options::new(self.width).initial_indent("  ").subsequent_indent("  ");
+                let mut text = format!("Error: {}", message);
+                if let Some(hint) = hint {
+                    text.push_str(&format!("\nHint: {}", hint));
+                }
+                text.lines()
+                    .flat_map(|line| wrap(line, &options))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            ErrorStyle::Box => Self::boxed(kind, &message, hint, self.width),
+        };
+        text.color(self.color).to_string()
+    }
+
+    fn boxed(kind: &str, message: &str, hint: Option<&str>, width: usize) -> String {
+        let inner = width.saturating_sub(6).max(20);
+        let mut body: Vec<String> = wrap(message, inner).into_iter().map(|line| line.into_owned()).collect();
+        if let Some(hint) = hint {
+            body.push(String::new());
+            body.extend(wrap(&format!("Hint: {}", hint), inner).into_iter().map(|line| line.into_owned()));
+        }
+
+        let title = format!("─ {} ", kind);
+        let title_width = textwrap::core::display_width(&title);
+        let mut output = format!("  ┌{}{}┐\n", title, "─".repeat((inner + 2).saturating_sub(title_width)));
+        for line in &body {
+            let padding = inner.saturating_sub(textwrap::core::display_width(line));
+            output.push_str(&format!("  │ {}{} │\n", line, " ".repeat(padding)));
+        }
+        output.push_str(&format!("  └{}┘", "─".repeat(inner + 2)));
+        output
+    }
+}
+
+fn print_error(error: &anyhow::Error, formatter: &ErrorFormatter) {
+    println!();
+    println!("{}", formatter.format(error));
+    println!();
+}
+
+/// Where command history is kept, in `home`, or `None` when history is
+/// off.
+fn history_file(config: &Config, home: Option<PathBuf>) -> Option<PathBuf> {
+    config.history.unwrap_or(true).then(|| {
+        home.map(|mut path| {
+            path.push(".mistral_history");
+            path
+        })
+        .unwrap_or_else(|| ".mistral_history".into())
+    })
+}
+
+/// The store conversations are saved to, in `dir`, or `None` when history
+/// is off.
+fn session_store(config: &Config, dir: PathBuf) -> Option<Box<dyn SessionStore>> {
+    config
+        .history
+        .unwrap_or(true)
+        .then(|| Box::new(FileSessionStore::new(dir)) as Box<dyn SessionStore>)
+}
+
+/// What the user asked for on this turn.
+enum Turn {
+    Send(String),
+    Regenerate(Option<Params>),
+}
+
+async fn chat_loop(
+    mut session: ChatSession,
+    config: &Config,
+    idle_timeout: Option<Duration>,
+    mut system_file: Option<SystemPromptFile>,
+    notice: Option<String>,
+) -> Result<()> {
+    let line_ending = config.line_endings.unwrap_or_default();
+    let stream = config.stream.unwrap_or(false);
+    let clear_screen = config.clear_screen.unwrap_or(true);
+    // Set after each exchange in scrollback mode, so the next prompt is
+    // preceded by a separator
+    let mut separate = false;
+    
+    // Changed by /width
+    let mut width = terminal_width();
+
+    let mut renderer = build_renderer(config, width);
+    let mut errors = ErrorFormatter::new(&config.errors, width);
+    let prefixes = PrefixFilter::new(config.strip_prefixes.as_deref().unwrap_or_default())?;
+    let refusals = RefusalDetector::new(&config.refusals)?;
+
+    // Define command box
+    let command_box = "\
+┌──────────────────────────────────────┐\n\
+│          Available Commands          │\n\
+├──────────────────────────────────────┤\n\
+│    `exit`  - Quit the application    │\n\
+├──────────────────────────────────────┤\n\
+│    `clear` - Clear the screen        │\n\
+├──────────────────────────────────────┤\n\
+│    `new`   - Start a new chat        │\n\
+├──────────────────────────────────────┤\n\
+│    `/help` - List chat commands      │\n\
+└──────────────────────────────────────┘";
+
+    // Function to show command box
+    let show_command_box = || {
+        println!("{}", command_box.green());
+        println!();
+    };
+
+    // Show the banner, then the initial welcome message
+    clearscreen::clear()?;
+    let banner = config.banner.render(&renderer);
+    if let Some(banner) = &banner {
+        println!("{}", line_ending.apply(banner));
+        println!();
+    }
+    if let Some(notice) = &notice {
+        println!("{}", notice);
+        println!();
+    }
+    if session.messages.is_empty() {
+        let welcome_message = "I am Mistral Chat AI, a helpful and respectful assistant\npowered by Mistral. Here are some ways I can assist you:\n\n• Provide information and answer questions on a wide\nrange of topics\n• Generate ideas, suggestions, and recommendations\n\nI'm ready to help! How can I assist you today?";
+
+        print!("{}", renderer.render(welcome_message).cyan());
+        println!("\n");
+        show_command_box();
+        print!("{}", "> ".blue().bold());
+        io::stdout().flush()?;
+    } else {
+        show_command_box();
+        print!("{}", "> ".blue().bold());
+        io::stdout().flush()?;
+    }
+
+    // Configure rustyline editor with history
+    let mut rl = DefaultEditor::new()?;
+    rl.set_max_history_size(100)?;
+    
+    // Load history from file if it exists, unless history is off
+    let history_file = history_file(config, dirs::home_dir());
+
+    if let Some(file) = history_file.as_ref().filter(|file| file.exists()) {
+        let _ = rl.load_history(file);
+    }
+
+    let terminal_mode = TerminalMode::capture();
+    let mut editor = Some(rl);
+    // The banner has just been shown for the first prompt
+    let mut repeat_banner = false;
+    
+    loop {
+        if separate {
+            separate = false;
+            if let Some(rule) = config.separator.before_next_turn(&session, width) {
+                println!("{}", rule);
+            }
+        }
+        if let Some(banner) = banner.as_ref().filter(|_| repeat_banner) {
+            println!("{}", line_ending.apply(banner));
+        }
+        repeat_banner = config.banner.every_turn();
+
+        let prompt = format!("{}", "> ".blue().bold());
+        let line = match read_input(&mut editor, &prompt, idle_timeout).await? {
+            InputEvent::Line(line) => line,
+            event => exit_abandoned_input(&terminal_mode, &event),
+        };
+        let Some(rl) = editor.as_mut() else {
+            break;
+        };
+        match line {
+            Ok(line) => {
+                let input = line.trim();
+                if input.eq_ignore_ascii_case("exit") {
+                    // Save history before exiting
+                    if let Some(file) = &history_file {
+                        let _ = rl.save_history(file);
+                    }
+                    break;
+                } else if input.eq_ignore_ascii_case("clear") {
+                    clearscreen::clear()?;
+                    show_command_box();
+                    continue;
+                } else if input.eq_ignore_ascii_case("new") {
+                    session.clear();
+                    clearscreen::clear()?;
+                    show_command_box();
+                    println!("{}", "Starting a fresh conversation...".green());
+                    print!("{}", "> ".blue().bold());
+                    io::stdout().flush()?;
+                    continue;
+                }
+
+                // Enter on an empty line just shows the prompt again
+                if input.is_empty() {
+                    continue;
+                }
+
+                // Add valid input to history, saving straight away so it
+                // survives an idle timeout or signal at the next prompt
+                if let Some(file) = &history_file {
+                    rl.add_history_entry(input)?;
+                    let _ = rl.save_history(file);
+                }
+
+                let turn = if let Some(command) = input.strip_prefix('/') {
+                    let (name, args) = command
+                        .split_once(char::is_whitespace)
+                        .map_or((command, ""), |(name, args)| (name, args.trim()));
+                    match name.to_ascii_lowercase().as_str() {
+                        "help" => {
+                            println!("{}", COMMAND_HELP.green());
+                            println!();
+                            continue;
+                        }
+                        "sessions" => {
+                            match session.list_saved() {
+                                Ok(ids) if ids.is_empty() => println!("{}", "No saved conversations yet".green()),
+                                Ok(ids) => {
+                                    for id in ids {
+                                        let marker = if id == session.id { " (current)" } else { "" };
+                                        println!("{}", format!("  {}{}", id, marker).green());
+                                    }
+                                }
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            println!();
+                            continue;
+                        }
+                        "load" => {
+                            if args.is_empty() {
+                                println!("{}", "Usage: /load <name>".red());
+                                continue;
+                            }
+                            match session.load(args) {
+                                Ok(()) => println!(
+                                    "{}",
+                                    format!("Resumed `{}` ({} messages)", session.id, session.messages.len()).green()
+                                ),
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            continue;
+                        }
+                        "fork" => {
+                            if args.is_empty() {
+                                println!("{}", "Usage: /fork <name>".red());
+                                continue;
+                            }
+                            let original = session.id.clone();
+                            match session.fork(args) {
+                                Ok(()) => println!(
+                                    "{}",
+                                    format!("Forked `{}` as `{}`; new messages go to the fork", original, session.id).green()
+                                ),
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            continue;
+                        }
+                        "ping" => {
+                            check_connection(&session.client).await;
+                            println!();
+                            continue;
+                        }
+                        "window" => {
+                            if args.eq_ignore_ascii_case("off") {
+                                session.window = None;
+                            } else if !args.is_empty() {
+                                match args.parse::<usize>() {
+                                    Ok(turns) => session.window = Some(turns),
+                                    Err(_) => {
+                                        println!("{}", "Usage: /window [turns|off]".red());
+                                        continue;
+                                    }
+                                }
+                            }
+                            match session.window {
+                                Some(turns) => println!("{}", format!("Sending the last {} turn(s) with each request", turns).green()),
+                                None => println!("{}", "Sending the whole conversation with each request".green()),
+                            }
+                            continue;
+                        }
+                        "lang" => {
+                            if args.eq_ignore_ascii_case("off") {
+                                session.language = None;
+                            } else if !args.is_empty() {
+                                if !renderer.knows_language(args) {
+                                    println!("{}", format!("Unknown language `{}`", args).red());
+                                    continue;
+                                }
+                                session.language = Some(args.to_ascii_lowercase());
+                            }
+                            match &session.language {
+                                Some(language) => println!("{}", format!("Untagged code blocks are highlighted as {}", language).green()),
+                                None => println!("{}", "Guessing the language of untagged code blocks from each message".green()),
+                            }
+                            continue;
+                        }
+                        "width" => {
+                            if args.eq_ignore_ascii_case("auto") {
+                                width = terminal_width();
+                            } else if !args.is_empty() {
+                                match args.parse::<usize>() {
+                                    Ok(columns) if WIDTH_RANGE.contains(&columns) => width = columns,
+                                    _ => {
+                                        println!(
+                                            "{}",
+                                            format!(
+                                                "Usage: /width <n|auto>, with n from {} to {}",
+                                                WIDTH_RANGE.start(),
+                                                WIDTH_RANGE.end()
+                                            )
+                                            .red()
+                                        );
+                                        continue;
+                                    }
+                                }
+                            }
+                            renderer.set_width(width);
+                            errors.width = width;
+                            println!("{}", format!("Replies are rendered {} columns wide", width).green());
+                            continue;
+                        }
+                        "wrap" => {
+                            match args.to_ascii_lowercase().as_str() {
+                                "on" => renderer.options.no_wrap = false,
+                                "off" => renderer.options.no_wrap = true,
+                                "" => {}
+                                _ => {
+                                    println!("{}", "Usage: /wrap [on|off]".red());
+                                    continue;
+                                }
+                            }
+                            if renderer.options.no_wrap {
+                                println!("{}", "Prose is left unwrapped, with the reply's own line breaks".green());
+                            } else {
+                                println!("{}", format!("Prose is wrapped to {} columns", width).green());
+                            }
+                            continue;
+                        }
+                        "writecode" => {
+                            let parsed = args
+                                .split_once(char::is_whitespace)
+                                .and_then(|(index, path)| Some((index.parse::<usize>().ok()?, path.trim())));
+                            let Some((index, path)) = parsed else {
+                                println!("{}", "Usage: /writecode <index> <path>".red());
+                                continue;
+                            };
+                            let Some(reply) = session.last_reply() else {
+                                println!("{}", "There is no reply to take code from yet".red());
+                                continue;
+                            };
+                            match write_code_block(reply, index, Path::new(path), &renderer) {
+                                Ok(written) => println!("{}", format!("Wrote code block {} to {}", index, written.display()).green()),
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            continue;
+                        }
+                        "pipe" => {
+                            let parsed = args
+                                .split_once(char::is_whitespace)
+                                .and_then(|(index, command)| Some((index.parse::<usize>().ok()?, command.trim())));
+                            let Some((index, command)) = parsed else {
+                                println!("{}", "Usage: /pipe <index> <command>".red());
+                                continue;
+                            };
+                            let Some(reply) = session.last_reply() else {
+                                println!("{}", "There is no reply to take code from yet".red());
+                                continue;
+                            };
+                            let block = match code_block(reply, index) {
+                                Ok(block) => block,
+                                Err(e) => {
+                                    print_error(&e, &errors);
+                                    continue;
+                                }
+                            };
+
+                            // Never run anything without a yes
+                            let lines = block.code.lines().count();
+                            let question = format!(
+                                "Pipe code block {} ({} line{}) into `{}`? [y/N] ",
+                                index,
+                                lines,
+                                if lines == 1 { "" } else { "s" },
+                                command
+                            );
+                            let answer = read_input(&mut editor, &question.yellow().to_string(), idle_timeout).await?;
+                            if !answered_yes(&answer) {
+                                println!("{}", "Not run".green());
+                                if !matches!(answer, InputEvent::Line(_)) {
+                                    exit_abandoned_input(&terminal_mode, &answer);
+                                }
+                                continue;
+                            }
+                            match pipe_code(&block.code, command).await {
+                                Ok(output) => {
+                                    print!("{}", String::from_utf8_lossy(&output.stdout));
+                                    if !output.stderr.is_empty() {
+                                        print!("{}", String::from_utf8_lossy(&output.stderr).yellow());
+                                    }
+                                    if output.status.success() {
+                                        println!("{}", format!("`{}` finished", command).green());
+                                    } else {
+                                        println!("{}", format!("`{}` failed ({})", command, output.status).red());
+                                    }
+                                }
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            println!();
+                            continue;
+                        }
+                        "model" => {
+                            if !args.is_empty() {
+                                session.client.model = args.to_string();
+                                session.client.route = None;
+                            }
+                            match &session.client.route {
+                                Some(rules) => println!(
+                                    "{}",
+                                    format!("Using {}, or {} for demanding prompts", session.client.model, rules.model).green()
+                                ),
+                                None => println!("{}", format!("Using {} for every request", session.client.model).green()),
+                            }
+                            continue;
+                        }
+                        "history" => {
+                            print_history(&session, width);
+                            continue;
+                        }
+                        "continue" => {
+                            let Ok(index) = args.parse::<usize>() else {
+                                println!("{}", "Usage: /continue <message number> (see /history)".red());
+                                continue;
+                            };
+                            match session.rewind_to(index) {
+                                Ok(dropped) => {
+                                    save_session(&session);
+                                    println!(
+                                        "{}",
+                                        format!("Rewound to message {}, dropping {} after it. Carry on from there.", index, dropped).green()
+                                    );
+                                }
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            continue;
+                        }
+                        "source" => {
+                            let Ok(index) = args.parse::<usize>() else {
+                                println!("{}", "Usage: /source <message number> (see /history)".red());
+                                continue;
+                            };
+                            match session.reply(index) {
+                                Ok(reply) => {
+                                    write_source(&mut io::stdout(), reply)?;
+                                    println!();
+                                    println!();
+                                }
+                                Err(e) => print_error(&e, &errors),
+                            }
+                            continue;
+                        }
+                        "stats" => {
+                            print_stats(&session);
+                            continue;
+                        }
+                        "ran" => {
+                            let parsed = args
+                                .split_once(char::is_whitespace)
+                                .and_then(|(index, status)| Some((index.parse::<usize>().ok()?, status.trim())))
+                                .filter(|(_, status)| !status.is_empty());
+                            let Some((index, status)) = parsed else {
+                                println!("{}", "Usage: /ran <index> <status or output>".red());
+                                continue;
+                            };
+                            let Some(reply) = session.last_reply() else {
+                                println!("{}", "There is no reply to take code from yet".red());
+                                continue;
+                            };
+                            match code_block(reply, index) {
+                                Ok(block) => Turn::Send(ran_follow_up(&block, index, status)),
+                                Err(e) => {
+                                    print_error(&e, &errors);
+                                    continue;
+                                }
+                            }
+                        }
+                        "retry" => Turn::Regenerate(None),
+                        "regenerate" if args.is_empty() => Turn::Regenerate(None),
+                        "regenerate" => match args.parse::<f32>() {
+                            Ok(temperature) => Turn::Regenerate(Some(Params {
+                                temperature: Some(temperature),
+                                ..Params::default()
+                            })),
+                            Err(_) => {
+                                println!("{}", "Usage: /regenerate [temperature]".red());
+                                continue;
+                            }
+                        },
+                        _ => {
+                            println!("{}", format!("Unknown command `/{}`. Type /help for a list.", name).red());
+                            continue;
+                        }
+                    }
+                } else {
+                    Turn::Send(input.to_string())
+                };
+
+                let mut reload_notice = None;
+                if let Some(file) = system_file.as_mut() {
+                    match file.reload_if_changed() {
+                        Ok(Some(prompt)) => {
+                            session.system_prompt = Some(prompt);
+                            reload_notice = Some(
+                                format!("Reloaded the system prompt from {}", file.path.display()).green().to_string(),
+                            );
+                        }
+                        Ok(None) => {}
+                        // Keep using the previous prompt until the file is readable again
+                        Err(e) => reload_notice = Some(format!("\n{}\n", errors.format(&e))),
+                    }
+                }
+                // A clear screen would wipe the notice, so it waits until
+                // after the clear
+                if !clear_screen {
+                    if let Some(notice) = reload_notice.take() {
+                        println!("{}", notice);
+                    }
+                }
+
+                if let (true, Turn::Send(input)) = (stream, &turn) {
+                    if clear_screen {
+                        clearscreen::clear()?;
+                        show_command_box();
+
+                        print!("{}", "> ".blue().bold());
+                        println!("{}", input);
+                        if let Some(notice) = reload_notice.take() {
+                            println!("{}", notice);
+                        }
+                    }
+                    println!();
+                    print_route(&session.client, input);
+                    io::stdout().flush()?;
+
+                    // Print each block as soon as the stream completes it
+                    let language_hint = session.language_hint(input);
+                    let mut at_start = true;
+                    let mut print_block = |block: StreamBlock| {
+                        // Only the opening of the reply is filtered
+                        let markdown = if at_start {
+                            prefixes.strip(&block.markdown)
+                        } else {
+                            Cow::Borrowed(block.markdown.as_str())
+                        };
+                        if markdown.trim().is_empty() {
+                            return;
+                        }
+                        at_start = false;
+                        let rendered = renderer.render_with_hint(&markdown, language_hint.as_deref());
+                        let separator = if block.continues_list { "\n" } else { "\n\n" };
+                        print!("{}", line_ending.apply(&format!("{}{}", rendered.cyan(), separator)));
+                        let _ = io::stdout().flush();
+                    };
+                    let mut markdown = MarkdownStream::default();
+                    let result = session
+                        .send_streaming(input, &mut |delta| markdown.push(delta).into_iter().for_each(&mut print_block))
+                        .await;
+                    markdown.finish().into_iter().for_each(&mut print_block);
+                    if discard_typeahead() {
+                        println!("{}", TYPEAHEAD_NOTICE.yellow());
+                    }
+
+                    match result {
+                        Ok((response, _)) => {
+                            // The refusal is already on screen, so any
+                            // second try is shown after it
+                            let mut declined = refusals.declined(&response);
+                            if declined && refusals.regenerates() {
+                                println!("{}", "  [model declined, regenerating]".dimmed());
+                                match session.regenerate_last(None).await {
+                                    Ok(again) => {
+                                        declined = refusals.declined(&again);
+                                        let rendered = renderer.render_with_hint(&prefixes.strip(&again), language_hint.as_deref());
+                                        print!("{}", line_ending.apply(&format!("{}\n\n", rendered.cyan())));
+                                    }
+                                    Err(e) => print_error(&e, &errors),
+                                }
+                            }
+                            if declined {
+                                println!("{}", REFUSAL_BADGE.dimmed());
+                            }
+                            print_context_trimmed(&session);
+                            save_session(&session);
+                            if clear_screen {
+                                print!("{}", "> ".blue().bold());
+                                io::stdout().flush()?;
+                            }
+                        }
+                        Err(e) => print_error(&e, &errors),
+                    }
+                    separate = !clear_screen;
+                    continue;
+                }
+
+                print!("{}", "Thinking...".yellow());
+                io::stdout().flush()?;
+
+                let result = match turn {
+                    Turn::Send(input) => session
+                        .send(&input)
+                        .await
+                        .map(|(response, language_hint)| (response, session.language.clone().or(language_hint))),
+                    Turn::Regenerate(params) => session.regenerate_last(params).await.map(|response| {
+                        let language_hint = session
+                            .last_user_message()
+                            .and_then(|input| session.language_hint(input));
+                        (response, language_hint)
+                    }),
+                };
+                // Regenerate once if the model declined, keeping the refusal
+                // and reporting the error after it if that fails
+                let mut regenerate_error = None;
+                let result = match result {
+                    Ok((response, language_hint)) if refusals.regenerates() && refusals.declined(&response) => {
+                        match session.regenerate_last(None).await {
+                            Ok(again) => Ok((again, language_hint)),
+                            Err(e) => {
+                                regenerate_error = Some(e);
+                                Ok((response, language_hint))
+                            }
+                        }
+                    }
+                    result => result,
+                };
+                let discarded = discard_typeahead();
+
+                match result {
+                    Ok((response, language_hint)) => {
+                        let prompt = session.last_user_message().unwrap_or_default();
+                        if clear_screen {
+                            clearscreen::clear()?;
+                            show_command_box();
+
+                            print!("{}", "> ".blue().bold());
+                            println!("{}", prompt);
+                            if let Some(notice) = reload_notice.take() {
+                                println!("{}", notice);
+                            }
+                            println!();
+                        } else {
+                            print!("\r\x1B[2K"); // Clear "Thinking..." line
+                        }
+                        print_route(&session.client, prompt);
+                        
+                        let rendered = renderer.render_with_hint(&prefixes.strip(&response), language_hint.as_deref());
+                        print!("{}", line_ending.apply(&rendered.cyan().to_string()));
+                        println!();
+                        if refusals.declined(&response) {
+                            println!("{}", REFUSAL_BADGE.dimmed());
+                        }
+                        if let Some(e) = &regenerate_error {
+                            print_error(e, &errors);
+                        } else {
+                            println!();
+                        }
+                        if discarded {
+                            println!("{}", TYPEAHEAD_NOTICE.yellow());
+                        }
+                        print_context_trimmed(&session);
+                        save_session(&session);
+                        
+                        if clear_screen {
+                            print!("{}", "> ".blue().bold());
+                            io::stdout().flush()?;
+                        }
+                    }
+                    Err(e) => {
+                        print!("\r\x1B[2K"); // Clear "Thinking..." line
+                        if let Some(notice) = reload_notice.take() {
+                            println!("{}", notice);
+                        }
+                        print_error(&e, &errors);
+                        if discarded {
+                            println!("{}", TYPEAHEAD_NOTICE.yellow());
+                        }
+                    }
+                }
+                separate = !clear_screen;
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("Use 'exit' to quit");
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                break;
+            }
+            Err(err) => {
+                println!("Error: {}", err);
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+
+    let cli = Cli::parse();
+    let mut config = Config::load()?;
+    if cli.no_history {
+        config.history = Some(false);
+    }
+
+    let api_key = resolve_api_key(env::var("MISTRAL_API_KEY").ok(), &config)?;
+    let mut client = MistralClient::new(api_key);
+    if let Some(base_url) = &config.base_url {
+        client = client.with_base_url(base_url);
+    }
+    if let Some(path) = &config.completions_path {
+        client = client.with_completions_path(path);
+    }
+    if let Some(scheme) = &config.auth {
+        client = client.with_auth_scheme(scheme.clone());
+    }
+    if let Some(model) = &config.model {
+        client = client.with_model(model);
+    }
+    if let Some(limit) = config.max_response_bytes {
+        client = client.with_max_response_bytes(limit);
+    }
+    if config.compress_requests.unwrap_or(false) {
+        client = client.with_request_compression();
+    }
+    if let Some(markers) = &config.stream_done_markers {
+        client = client.with_stream_done_markers(markers.clone());
+    }
+    if let Some(sink) = MetricsSink::new(&config.metrics) {
+        client = client.with_metrics(sink);
+    }
+    if let Some(rules) = &config.routing {
+        client = client.with_auto_route(rules.clone());
+    }
+    if let Some(bias) = &config.logit_bias {
+        client = client.with_logit_bias(bias.clone())?;
+    }
+
+    if cli.check {
+        let ok = check_connection(&client).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if let Some(prompt) = &cli.complete {
+        let line_ending = config.line_endings.unwrap_or_default();
+        let text = client.complete(prompt).await?;
+        print!("{}", line_ending.apply(&format!("{}\n", text)));
+        return Ok(());
+    }
+
+    let mut session = ChatSession::new(client);
+    if let Some(store) = session_store(&config, FileSessionStore::default_dir()) {
+        session = session.with_store(store);
+    }
+    session.window = config.window;
+    session.system_prompt = config.system_prompt.clone();
+    session.language = config.language.clone();
+    session.max_context_tokens = config.max_context_tokens;
+    session.max_messages = config.max_messages;
+
+    let mut system_file = None;
+    if let Some(path) = &cli.system_file {
+        let (file, prompt) = SystemPromptFile::open(path, cli.watch)?;
+        session.system_prompt = Some(prompt);
+        system_file = Some(file);
+    }
+    if let Some(path) = cli.examples.as_ref().or(config.few_shot.as_ref()) {
+        session.examples = load_examples(path)?;
+    }
+
+    let mut notice = None;
+    if cli.continue_last {
+        notice = Some(if session.load_latest()? {
+            format!("Resumed `{}` ({} messages)", session.id, session.messages.len()).green().to_string()
+        } else {
+            "No saved conversation to continue; starting a fresh one".yellow().to_string()
+        });
+    }
+
+    if let Some(template) = &cli.template {
+        let line_ending = config.line_endings.unwrap_or_default();
+        return run_template(&mut session, template, cli.vars, line_ending).await;
+    }
+
+    if let Some(prompt) = &cli.sweep {
+        return run_sweep(&mut session, &config, prompt, &cli.models, &cli.temperatures).await;
+    }
+
+    if cli.tui {
+        #[cfg(feature = "tui")]
+        return tui::run(session, &config, system_file, notice).await;
+        #[cfg(not(feature = "tui"))]
+        eprintln!("{}", "This build has no TUI (enable the `tui` feature); using the line interface.".yellow());
+    }
+
+    chat_loop(session, &config, cli.idle_timeout.map(Duration::from_secs), system_file, notice).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Width the rendering snapshots are taken at.
+    const SNAPSHOT_WIDTH: usize = 60;
+
+    /// `text` without SGR or OSC 8 escape sequences, as a terminal with
+    /// no colour would show it.
+    fn strip_ansi(text: &str) -> String {
+        let mut plain = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\x1B' {
+                plain.push(c);
+                continue;
+            }
+            match chars.next() {
+                // SGR: ESC [ parameters m
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if c == 'm' {
+                            break;
+                        }
+                    }
+                }
+                // OSC 8 hyperlink: ESC ] 8;; url ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x1B' {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        plain
+    }
+
+    /// `markdown` rendered at `width` columns, without styling.
+    fn render_plain(markdown: &str, width: usize) -> String {
+        colored::control::set_override(false);
+        strip_ansi(&MarkdownRenderer::new(width).render(markdown))
+    }
+
+    /// A fresh, empty directory under the system temp dir for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mistral-small-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A session whose client is never used to send anything.
+    fn offline_session() -> ChatSession {
+        ChatSession::new(MistralClient::new(String::new()))
+    }
+
+    /// Appends a question and its answer, as a completed `send` would.
+    fn push_turn(session: &mut ChatSession, question: &str, answer: &str) {
+        session.messages.push(ChatMessage::now("user", question.to_string()));
+        session.messages.push(ChatMessage::now("assistant", answer.to_string()));
+    }
+
+    /// Lines sent down a channel, standing in for the user at a terminal.
+    /// Reading waits until a line is sent; closing the channel is Ctrl-D.
+    impl LineSource for std::sync::mpsc::Receiver<String> {
+        fn read_line(&mut self, _prompt: &str) -> rustyline::Result<String> {
+            self.recv().map_err(|_| ReadlineError::Eof)
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_too_long_for_input_times_out() {
+        let (typing, lines) = std::sync::mpsc::channel();
+        let mut source = Some(lines);
+
+        typing.send("hello".to_string()).unwrap();
+        let event = read_input(&mut source, "> ", Some(Duration::from_secs(5))).await.unwrap();
+        assert!(matches!(event, InputEvent::Line(Ok(ref line)) if line == "hello"));
+        assert!(source.is_some(), "the source is handed back after a line");
+
+        let event = read_input(&mut source, "> ", Some(Duration::from_millis(50))).await.unwrap();
+        assert!(matches!(event, InputEvent::IdleTimeout));
+        assert!(source.is_none(), "the source stays with the abandoned read");
+        assert!(read_input(&mut source, "> ", None).await.is_err());
+
+        // Let the abandoned read finish so the runtime can shut down
+        drop(typing);
+    }
+
+    #[tokio::test]
+    async fn only_yes_confirms() {
+        for yes in ["y", "yes", " Y ", "YES"] {
+            assert!(answered_yes(&InputEvent::Line(Ok(yes.to_string()))), "{:?}", yes);
+        }
+        for no in ["", "n", "no", "yess", "y y"] {
+            assert!(!answered_yes(&InputEvent::Line(Ok(no.to_string()))), "{:?}", no);
+        }
+        assert!(!answered_yes(&InputEvent::Line(Err(ReadlineError::Interrupted))));
+        assert!(!answered_yes(&InputEvent::Line(Err(ReadlineError::Eof))));
+        assert!(!answered_yes(&InputEvent::Shutdown));
+
+        // Walking away from the question doesn't run anything either
+        let (typing, lines) = std::sync::mpsc::channel::<String>();
+        let mut source = Some(lines);
+        let answer = read_input(&mut source, "[y/N] ", Some(Duration::from_millis(50))).await.unwrap();
+        assert!(!answered_yes(&answer));
+        drop(typing);
+    }
+
+    #[tokio::test]
+    async fn closed_input_is_reported_as_end_of_file() {
+        let (typing, lines) = std::sync::mpsc::channel::<String>();
+        drop(typing);
+        let mut source = Some(lines);
+        let event = read_input(&mut source, "> ", None).await.unwrap();
+        assert!(matches!(event, InputEvent::Line(Err(ReadlineError::Eof))));
+    }
+
+    #[test]
+    fn templates_fill_in_variables() {
+        let vars = HashMap::from([("name".to_string(), "Ada".to_string())]);
+        let env = |name: &str| (name == "LANG_NAME").then(|| "Rust".to_string());
+
+        assert_eq!(
+            render_template("Hi {{ name }}, explain {{LANG_NAME}} in {{style|plain words}}.", &vars, env).unwrap(),
+            "Hi Ada, explain Rust in plain words."
+        );
+        // Given variables win over the environment and defaults
+        assert_eq!(render_template("{{name|Bob}}", &vars, |_| Some("env".to_string())).unwrap(), "Ada");
+        assert_eq!(render_template("{{missing|}}!", &vars, env).unwrap(), "!");
+
+        let error = render_template("Dear {{missing}}", &vars, env).unwrap_err();
+        assert!(error.to_string().contains("`missing` is not defined"), "{}", error);
+        assert!(render_template("{{bad name}}", &vars, env).is_err());
+        assert_eq!(render_template("left {{ open", &vars, env).unwrap(), "left {{ open");
+    }
+
+    /// Answers one request per response in `responses` (a status and a
+    /// JSON body), in order, on a local port. Returns the base URL, and
+    /// the head and body of each request as it arrives.
+    fn mock_server(responses: Vec<(u16, String)>) -> (String, std::sync::mpsc::Receiver<(String, Vec<u8>)>) {
+        use std::io::{BufRead, BufReader, Read};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    if reader.read_line(&mut head).unwrap() == 0 {
+                        break;
+                    }
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length").then(|| value.trim().parse().ok())?
+                    })
+                    .unwrap_or(0);
+                let mut request_body = vec![0; length];
+                reader.read_exact(&mut request_body).unwrap();
+                let _ = sender.send((head, request_body));
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// The body of a chat completion whose reply is `content`.
+    fn chat_reply(content: &str) -> String {
+        serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": content}}],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 3},
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn regenerating_replaces_only_the_last_reply() {
+        let (url, requests) = mock_server(vec![
+            (200, chat_reply("a better answer")),
+            (500, r#"{"message": "overloaded"}"#.to_string()),
+        ]);
+        let client = MistralClient::new("key".to_string()).with_base_url(url);
+        let mut session = ChatSession::new(client);
+        assert!(session.regenerate_last(None).await.is_err(), "nothing to regenerate yet");
+        push_turn(&mut session, "q1", "a1");
+        push_turn(&mut session, "q2", "a2");
+
+        let override_params = Params {
+            temperature: Some(1.2),
+            ..Params::default()
+        };
+        assert_eq!(session.regenerate_last(Some(override_params)).await.unwrap(), "a better answer");
+        let contents: Vec<&str> = session.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["q1", "a1", "q2", "a better answer"]);
+
+        // The old reply isn't sent back, and the override applies
+        let (_, body) = requests.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["messages"].as_array().unwrap().len(), 3);
+        assert!((body["temperature"].as_f64().unwrap() - 1.2).abs() < 1e-6);
+        assert_eq!(session.client.params.temperature, None, "only for that request");
+
+        // A failure keeps the reply there was
+        assert!(session.regenerate_last(None).await.is_err());
+        assert_eq!(session.messages.len(), 4);
+        assert_eq!(session.last_reply(), Some("a better answer"));
+    }
+
+    #[test]
+    fn carriage_returns_never_reach_the_output() {
+        assert_eq!(normalize_line_endings("a\r\nb\rc\n"), "a\nb\nc\n");
+        assert!(matches!(normalize_line_endings("a\nb"), Cow::Borrowed(_)));
+
+        let crlf = "# Title\r\n\r\nFirst line\r\nsecond line\r\n\r\n- one\r\n- two\r\n\r\n```sh\r\necho hi\r\n```\r\n";
+        let rendered = render_plain(crlf, SNAPSHOT_WIDTH);
+        assert!(!rendered.contains('\r'), "{:?}", rendered);
+        assert_eq!(rendered, render_plain(&crlf.replace("\r\n", "\n"), SNAPSHOT_WIDTH));
+
+        assert_eq!(LineEnding::Crlf.apply("a\nb\n"), "a\r\nb\r\n");
+        assert_eq!(LineEnding::Lf.apply("a\nb\n"), "a\nb\n");
+    }
+
+    #[test]
+    fn window_sends_only_the_last_turns() {
+        let mut session = offline_session();
+        session.system_prompt = Some("Be brief.".to_string());
+        for turn in 1..=5 {
+            push_turn(&mut session, &format!("q{}", turn), &format!("a{}", turn));
+        }
+        session.messages.push(ChatMessage::now("user", "now".to_string()));
+        let sent = |session: &ChatSession| {
+            session
+                .request_messages()
+                .into_iter()
+                .map(|msg| msg.content)
+                .collect::<Vec<_>>()
+        };
+
+        session.window = Some(2);
+        assert_eq!(sent(&session), ["Be brief.", "q4", "a4", "q5", "a5", "now"]);
+        session.window = Some(0);
+        assert_eq!(sent(&session), ["Be brief.", "now"]);
+        session.window = Some(10);
+        assert_eq!(sent(&session).len(), 12);
+        assert_eq!(session.messages.len(), 11, "the full history is kept");
+    }
+
+    #[test]
+    fn each_failure_gets_its_own_diagnosis() {
+        let status = |code: u16, body: &str| ApiError::from_response(reqwest::StatusCode::from_u16(code).unwrap(), body);
+        let cases = [
+            (status(401, ""), "Authentication error", "The API key was rejected"),
+            (status(403, ""), "Authentication error", "The API key was rejected"),
+            (status(404, ""), "Endpoint not found", "Check that base_url points at the API root"),
+            (status(429, ""), "Rate limited", "Rate limit reached"),
+            (status(500, ""), "Server error", "The server had a problem"),
+            (status(503, ""), "Server error", "The server had a problem"),
+            (status(400, r#"{"message": "bad temperature"}"#), "Request rejected", "The server rejected the request"),
+            (
+                status(400, r#"{"error": {"message": "maximum context length is 32768 tokens"}}"#),
+                "Conversation too long",
+                "The request is longer than the model accepts",
+            ),
+            (ApiError::InvalidUrl("nope".to_string()), "Invalid URL", "The base_url is not a valid URL"),
+            (ApiError::ResponseTooLarge { limit: 10 }, "Response too large", "raise max_response_bytes"),
+        ];
+        for (error, kind, diagnosis) in cases {
+            assert_eq!(error.kind(), kind, "{}", error);
+            assert!(error.diagnosis().contains(diagnosis), "{}: {}", error, error.diagnosis());
+        }
+
+        // The server's message is picked out of the usual JSON shapes
+        let message = |body: &str| match status(400, body) {
+            ApiError::Status { message, .. } => message,
+            _ => unreachable!(),
+        };
+        assert_eq!(message(r#"{"message": "a"}"#), "a");
+        assert_eq!(message(r#"{"error": {"message": "b"}}"#), "b");
+        assert_eq!(message(r#"{"error": "c"}"#), "c");
+        assert_eq!(message(r#"{"detail": [1]}"#), "[1]");
+        assert_eq!(message("  plain text\n"), "plain text");
+    }
+
+    #[test]
+    fn styles_are_closed_and_reopened_on_every_wrapped_line() {
+        let sentence = "\x1B[1mThis bold sentence is long enough that it has to be wrapped over several lines\x1B[22m done";
+        let options = Options::new(24).initial_indent("  ").subsequent_indent("  ");
+        let lines = wrap_styled(sentence, &options);
+        assert!(lines.len() >= 4, "{:?}", lines);
+
+        let (last, bold) = lines.split_last().unwrap();
+        for line in bold {
+            assert!(line.starts_with("  \x1B[1m"), "opened after the indent: {:?}", line);
+            assert!(line.ends_with("\x1B[22m"), "closed at the end: {:?}", line);
+            assert!(textwrap::core::display_width(line) <= 24, "{:?}", line);
+        }
+        assert!(last.ends_with("done"), "{:?}", last);
+        assert_eq!(
+            lines.iter().map(|line| strip_ansi(line).trim().to_string()).collect::<Vec<_>>().join(" "),
+            strip_ansi(sentence)
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_replies_are_refused() {
+        let reply = chat_reply(&"x".repeat(2000));
+        let (url, _requests) = mock_server(vec![(200, reply.clone()), (200, reply.clone()), (500, reply.clone())]);
+        let message = || vec![ChatMessage::now("user", "Hi".to_string())];
+
+        let client = MistralClient::new("key".to_string())
+            .with_base_url(url.clone())
+            .with_max_response_bytes(reply.len() - 1);
+        let error = client.send_message(message()).await.unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<ApiError>(), Some(ApiError::ResponseTooLarge { limit }) if *limit == reply.len() - 1),
+            "{}",
+            error
+        );
+
+        let client = MistralClient::new("key".to_string())
+            .with_base_url(url.clone())
+            .with_max_response_bytes(reply.len());
+        assert_eq!(client.send_message(message()).await.unwrap().0.len(), 2000);
+
+        // An error body is capped too
+        let client = MistralClient::new("key".to_string()).with_base_url(url).with_max_response_bytes(100);
+        let error = client.send_message(message()).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ApiError>(), Some(ApiError::ResponseTooLarge { .. })), "{}", error);
+    }
+
+    #[test]
+    fn project_config_is_found_above_and_merged_over_the_global_one() {
+        let root = temp_dir("project-config");
+        let nested = root.join("src").join("deep");
+        std::fs::create_dir_all(&nested).unwrap();
+        let project = root.join(PROJECT_CONFIG_FILE);
+        std::fs::write(&project, "model = \"mistral-large-latest\"\n").unwrap();
+        assert_eq!(Config::find_project_config(&nested), Some(project.clone()));
+        assert_eq!(Config::find_project_config(&root), Some(project.clone()));
+
+        // The nearest file wins
+        let closer = root.join("src").join(PROJECT_CONFIG_FILE);
+        std::fs::write(&closer, "window = 4\n").unwrap();
+        assert_eq!(Config::find_project_config(&nested), Some(closer));
+
+        let mut merged: toml::Table = toml::from_str("model = \"mistral-small\"\nwindow = 10\n").unwrap();
+        merge_tables(&mut merged, Config::read_table(&project).unwrap());
+        let config = Config::deserialize(merged).unwrap();
+        assert_eq!(config.model.as_deref(), Some("mistral-large-latest"));
+        assert_eq!(config.window, Some(10), "keys the project leaves out are kept");
+
+        let mut merged: toml::Table = toml::from_str("[errors]\ncolor = \"red\"\nstyle = \"box\"\n").unwrap();
+        merge_tables(&mut merged, toml::from_str("[errors]\nstyle = \"line\"\n").unwrap());
+        assert_eq!(merged["errors"]["style"].as_str(), Some("line"), "tables are merged key by key");
+        assert_eq!(merged["errors"]["color"].as_str(), Some("red"));
+    }
+
+    #[test]
+    fn streamed_paragraphs_are_released_once_complete() {
+        let mut stream = MarkdownStream::default();
+        assert!(stream.push("The first part of a paragraph, ").is_empty());
+        assert!(stream.push("then the middle,\nover two lines, ").is_empty());
+        let blocks = stream.push("and the end.\n\nNext");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            blocks[0].markdown,
+            "The first part of a paragraph, then the middle,\nover two lines, and the end.\n\n"
+        );
+        assert!(!blocks[0].continues_list);
+
+        // Nothing is released twice, and the rest comes out at the end
+        assert!(stream.push(" paragraph").is_empty());
+        assert_eq!(stream.finish().map(|block| block.markdown).as_deref(), Some("Next paragraph"));
+        assert!(stream.finish().is_none());
+
+        // A fence is held back until it closes, blank lines and all
+        let mut stream = MarkdownStream::default();
+        assert!(stream.push("```rust\nfn main() {\n\n").is_empty());
+        let blocks = stream.push("}\n```\n");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].markdown, "```rust\nfn main() {\n\n}\n```\n");
+    }
+
+    /// Keeps conversations in memory, standing in for another backend.
+    #[derive(Default)]
+    struct MemoryStore {
+        conversations: std::sync::Arc<std::sync::Mutex<HashMap<String, Vec<ChatMessage>>>>,
+    }
+
+    impl SessionStore for MemoryStore {
+        fn save(&self, conversation: &Conversation) -> Result<()> {
+            self.conversations
+                .lock()
+                .unwrap()
+                .insert(conversation.id.clone(), conversation.messages.clone());
+            Ok(())
+        }
+
+        fn load(&self, id: &str) -> Result<Conversation> {
+            let messages = self.conversations.lock().unwrap().get(id).cloned();
+            messages
+                .map(|messages| Conversation { id: id.to_string(), messages })
+                .ok_or_else(|| anyhow::anyhow!("no session `{}`", id))
+        }
+
+        fn list(&self) -> Result<Vec<String>> {
+            let mut ids: Vec<String> = self.conversations.lock().unwrap().keys().cloned().collect();
+            ids.sort();
+            Ok(ids)
+        }
+
+        fn latest(&self) -> Result<Option<String>> {
+            Ok(self.list()?.pop())
+        }
+    }
+
+    #[test]
+    fn sessions_save_to_and_load_from_any_store() {
+        let store = MemoryStore::default();
+        let conversations = store.conversations.clone();
+        let mut session = offline_session().with_store(Box::new(store));
+
+        session.save().unwrap();
+        assert!(conversations.lock().unwrap().is_empty(), "empty conversations are not saved");
+
+        push_turn(&mut session, "first question", "first answer");
+        session.save().unwrap();
+        let first = session.id.clone();
+
+        session.clear();
+        assert_ne!(session.id, first, "a new conversation gets a new id straight away");
+        push_turn(&mut session, "second question", "second answer");
+        session.save().unwrap();
+        let second = session.id.clone();
+
+        let store = session.store.as_ref().unwrap();
+        assert_eq!(store.list().unwrap(), [first.clone(), second.clone()]);
+        assert_eq!(store.latest().unwrap(), Some(second));
+
+        session.load(&first).unwrap();
+        assert_eq!(session.id, first);
+        let contents: Vec<&str> = session.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["first question", "first answer"]);
+        assert!(session.load("missing").is_err());
+    }
+
+    #[test]
+    fn session_ids_are_unique_and_valid() {
+        let ids: Vec<String> = (0..50).map(|_| new_session_id()).collect();
+        for id in &ids {
+            validate_session_id(id).unwrap();
+        }
+        let mut sorted = ids.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted, ids, "ids are distinct and in creation order");
+    }
+
+    #[tokio::test]
+    async fn rejected_keys_come_with_a_hint() {
+        let (url, _requests) = mock_server(vec![(401, r#"{"message": "Unauthorized"}"#.to_string())]);
+        let client = MistralClient::new("wrong".to_string()).with_base_url(url);
+        let error = client
+            .send_message(vec![ChatMessage::now("user", "Hi".to_string())])
+            .await
+            .unwrap_err();
+
+        let api_error = error.downcast_ref::<ApiError>().expect("an API error");
+        assert_eq!(api_error.kind(), "Authentication error");
+        assert!(api_error.diagnosis().contains("MISTRAL_API_KEY"));
+
+        let line = ErrorFormatter::new(
+            &ErrorConfig {
+                style: Some(ErrorStyle::Line),
+                color: None,
+            },
+            200,
+        );
+        let text = strip_ansi(&line.format(&error));
+        assert_eq!(
+            text,
+            "  Error: API returned 401 Unauthorized: Unauthorized\n  \
+             Hint: The API key was rejected. Check that MISTRAL_API_KEY is set to a valid key."
+        );
+
+        let boxed = strip_ansi(&ErrorFormatter::new(&ErrorConfig::default(), 80).format(&error));
+        assert!(boxed.contains("Authentication error"), "{}", boxed);
+        assert!(boxed.contains("The API key was rejected"), "{}", boxed);
+    }
+
+    #[test]
+    fn shortcodes_become_emoji_outside_code() {
+        assert_eq!(expand_shortcodes("Done :tada: :rocket:"), "Done 🎉 🚀");
+        assert_eq!(expand_shortcodes("at 10:30: :not_an_emoji:"), "at 10:30: :not_an_emoji:");
+
+        let renderer = MarkdownRenderer::new(SNAPSHOT_WIDTH).with_options(RenderOptions {
+            emoji_shortcodes: true,
+            ..RenderOptions::default()
+        });
+        let rendered = strip_ansi(&renderer.render("Shipped :tada: (type `:tada:` for this)\n\n```\necho :tada:\n```"));
+        assert!(rendered.contains("Shipped 🎉 (type `:tada:` for this)"), "{}", rendered);
+        assert!(rendered.contains("echo :tada:"), "{}", rendered);
+
+        // Off unless configured
+        assert!(render_plain("Shipped :tada:", SNAPSHOT_WIDTH).contains(":tada:"));
+    }
+
+    #[test]
+    fn session_language_applies_to_untagged_code() {
+        let mut session = offline_session();
+        assert_eq!(session.language_hint("How do I sort a vec in rust?").as_deref(), Some("rust"));
+        assert_eq!(session.language_hint("How do I sort a list?"), None);
+        session.language = Some("python".to_string());
+        assert_eq!(session.language_hint("How do I sort a vec in rust?").as_deref(), Some("python"));
+        assert_eq!(session.language_hint("How do I sort a list?").as_deref(), Some("python"));
+
+        let renderer = MarkdownRenderer::new(SNAPSHOT_WIDTH);
+        let untagged = "```\ndef add(a, b):\n    return a + b\n```";
+        let as_python = renderer.render_with_hint(untagged, Some("python"));
+        assert_ne!(as_python, renderer.render_with_hint(untagged, None));
+        assert_ne!(as_python, renderer.render_with_hint(untagged, Some("rust")));
+        // A tagged block keeps its own language
+        let tagged = "```rust\nfn add(a: i32) -> i32 { a }\n```";
+        assert_eq!(renderer.render_with_hint(tagged, Some("python")), renderer.render(tagged));
+    }
+
+    #[test]
+    fn code_blocks_are_written_to_a_file() {
+        let dir = temp_dir("write-code");
+        let renderer = MarkdownRenderer::new(SNAPSHOT_WIDTH);
+        let reply = "Setup:\n\n```sh\ncargo new demo\n```\n\nThen:\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n\n```\nplain\n```\n";
+
+        let path = write_code_block(reply, 2, &dir.join("main"), &renderer).unwrap();
+        assert_eq!(path, dir.join("main.rs"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fn main() {\n    println!(\"hi\");\n}\n");
+
+        // An extension that is given is kept
+        let path = write_code_block(reply, 1, &dir.join("setup.txt"), &renderer).unwrap();
+        assert_eq!(path, dir.join("setup.txt"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "cargo new demo\n");
+
+        let path = write_code_block(reply, 3, &dir.join("notes"), &renderer).unwrap();
+        assert_eq!(path, dir.join("notes"));
+
+        assert!(write_code_block(reply, 4, &dir.join("missing"), &renderer).is_err());
+        assert!(!dir.join("missing").exists());
+        assert!(write_code_block(reply, 1, &dir.join("no-such-dir").join("x.sh"), &renderer).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn typeahead_is_discarded_from_a_terminal() {
+        // SAFETY: plain pseudo-terminal calls on descriptors opened here
+        // and closed at the end.
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let name = std::ffi::CStr::from_ptr(libc::ptsname(master)).to_owned();
+            let slave = libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+            assert!(slave >= 0);
+
+            assert!(!discard_pending_input(slave), "nothing typed yet");
+            let typed = b"typed while waiting\n";
+            assert_eq!(libc::write(master, typed.as_ptr().cast(), typed.len()), typed.len() as isize);
+            // Give the line discipline a moment to pass the input along
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(discard_pending_input(slave));
+            let mut pending: libc::c_int = 0;
+            assert_eq!(libc::ioctl(slave, libc::FIONREAD, &mut pending), 0);
+            assert_eq!(pending, 0);
+            assert!(!discard_pending_input(slave), "already discarded");
+
+            libc::close(slave);
+            libc::close(master);
+        }
+
+        // A pipe isn't a terminal, so scripted input is never thrown away
+        let mut fds = [0; 2];
+        // SAFETY: pipe fills in two descriptors, closed straight after.
+        unsafe {
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            libc::write(fds[1], b"x\n".as_ptr().cast(), 2);
+            assert!(!discard_pending_input(fds[0]));
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        assert!(TYPEAHEAD_NOTICE.starts_with("Ignored input typed while waiting"));
+    }
+
+    #[test]
+    fn edited_system_prompt_file_changes_the_request() {
+        let dir = temp_dir("system-prompt");
+        let path = dir.join("prompt.md");
+        std::fs::write(&path, "Be brief.\r\n").unwrap();
+
+        let (mut file, prompt) = SystemPromptFile::open(&path, true).unwrap();
+        let mut session = offline_session();
+        session.system_prompt = Some(prompt);
+        session.messages.push(ChatMessage::now("user", "Hi".to_string()));
+        assert_eq!(session.request_messages()[0].content, "Be brief.");
+        assert_eq!(file.reload_if_changed().unwrap(), None);
+
+        std::fs::write(&path, "Answer in French,\r\nand be brief.\n").unwrap();
+        let prompt = file.reload_if_changed().unwrap().expect("the edit is noticed");
+        session.system_prompt = Some(prompt);
+        let request = session.request_messages();
+        assert_eq!(request[0].role, "system");
+        assert_eq!(request[0].content, "Answer in French,\nand be brief.");
+        assert_eq!(file.reload_if_changed().unwrap(), None);
+
+        let (mut unwatched, _) = SystemPromptFile::open(&path, false).unwrap();
+        std::fs::write(&path, "Something else entirely").unwrap();
+        assert_eq!(unwatched.reload_if_changed().unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.reload_if_changed().is_err());
+    }
+
+    #[test]
+    fn demanding_prompts_are_routed_to_the_larger_model() {
+        let rules: RouteRules = toml::from_str(
+            r#"
+            model = "mistral-large-latest"
+            min_chars = 200
+            code = true
+            keywords = ["Prove", "architecture"]
+            languages = ["rust"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.reason(&"x".repeat(250)), Some("250 characters long".to_string()));
+        assert_eq!(
+            rules.reason("Why does this fail?\nlet x = 5;\nprintln!(\"{}\", x);"),
+            Some("contains code".to_string())
+        );
+        assert_eq!(rules.reason("Fix this:\n```\nfoo()\n```"), Some("contains code".to_string()));
+        assert_eq!(rules.reason("prove that there are infinitely many primes"), Some("mentions \"Prove\"".to_string()));
+        assert_eq!(rules.reason("How do lifetimes work in Rust?"), Some("about rust".to_string()));
+        assert_eq!(rules.reason("What's the capital of France?"), None);
+        assert_eq!(rules.reason("How do I reverse a list in python?"), None);
+
+        let mut client = MistralClient::new(String::new());
+        assert_eq!(client.route("Prove it"), (DEFAULT_MODEL, None));
+        client.route = Some(rules);
+        assert_eq!(client.route("Prove it"), ("mistral-large-latest", Some("mentions \"Prove\"".to_string())));
+        assert_eq!(client.route("Hello"), (DEFAULT_MODEL, None));
+    }
+
+    #[tokio::test]
+    async fn sweep_sends_one_request_and_row_per_temperature() {
+        let (url, bodies) = mock_server(vec![
+            (200, chat_reply("Ownership means one owner.\n\nMore detail follows.")),
+            (200, chat_reply("Every value in Rust has exactly one owner at any time, and it is dropped when that owner goes away")),
+        ]);
+        let client = MistralClient::new("key".to_string()).with_base_url(url);
+        let messages = vec![ChatMessage::now("user", "Hi".to_string())];
+        let requests = sweep_requests(&["mistral-small".to_string()], &[Some(0.2), Some(0.9)], &messages);
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.model == "mistral-small" && r.messages.len() == 1));
+
+        let replies = client.send_batch(&requests).await;
+        assert!(replies.iter().all(|reply| reply.is_ok()));
+        // Requests go out together, so they may arrive in either order
+        let mut temperatures: Vec<f64> = bodies
+            .try_iter()
+            .map(|(_, body)| {
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                body["temperature"].as_f64().unwrap()
+            })
+            .collect();
+        temperatures.sort_by(f64::total_cmp);
+        assert_eq!(temperatures, [0.2, 0.9]);
+
+        let mut table = sweep_table(&requests, &replies);
+        assert_eq!(table.rows.len(), 2);
+        assert_eq!(table.headers.cells.len(), 5);
+        table.calculate_column_widths(120);
+        let rendered = strip_ansi(&table.render());
+        assert!(rendered.lines().all(|line| textwrap::core::display_width(line) <= 120));
+        assert!(rendered.contains("5/3") && rendered.contains("0.2") && rendered.contains("0.9"));
+        assert!(rendered.contains("Ownership means one owner. …"), "{}", rendered);
+        assert!(!rendered.contains("More detail"), "{}", rendered);
+        assert!(!rendered.contains("goes away"), "{}", rendered);
+
+        let failed = sweep_table(&requests[..1], &[Err(anyhow::anyhow!("timed out"))]);
+        let cells: Vec<&str> = failed.rows[0].cells.iter().map(|cell| cell.content.as_str()).collect();
+        assert_eq!(cells[2..], ["failed", "-", "-"]);
+    }
+
+    #[test]
+    fn configured_openings_are_stripped() {
+        let filter = PrefixFilter::new(&["Sure, here's ".to_string(), r"As an AI[^.]*\.".to_string()]).unwrap();
+        assert_eq!(filter.strip("Sure, here's the script:\n\n```sh\nls\n```"), "The script:\n\n```sh\nls\n```");
+        assert_eq!(filter.strip("  Sure, here's As an AI model, I think so. 42 is the answer."), "42 is the answer.");
+        assert!(matches!(filter.strip("Here it is, sure, here's more."), Cow::Borrowed(_)));
+        assert!(matches!(PrefixFilter::new(&[]).unwrap().strip("Sure, here's x"), Cow::Borrowed(_)));
+        assert!(PrefixFilter::new(&["(unclosed".to_string()]).is_err());
+    }
+
+    #[test]
+    fn token_estimate_grows_with_the_text() {
+        let message = |text: &str| ChatMessage::now("user", text.to_string());
+        let mut previous = MistralClient::estimate_tokens(&[message("")]);
+        assert_eq!(previous, TOKENS_PER_MESSAGE);
+        for len in 1..200 {
+            let estimate = MistralClient::estimate_tokens(&[message(&"a".repeat(len))]);
+            assert!(estimate >= previous, "estimate fell at {} characters", len);
+            previous = estimate;
+        }
+
+        // About four characters a token, counted in characters, not bytes
+        let text = "The quick brown fox jumps over the lazy dog";
+        assert_eq!(MistralClient::estimate_tokens(&[message(text)]), 11 + TOKENS_PER_MESSAGE);
+        assert_eq!(MistralClient::estimate_tokens(&[message("ééééé")]), 2 + TOKENS_PER_MESSAGE);
+        assert_eq!(
+            MistralClient::estimate_tokens(&[message(text), message(text)]),
+            2 * (11 + TOKENS_PER_MESSAGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn too_long_a_conversation_is_trimmed_and_retried_once() {
+        let too_long = r#"{"message": "Prompt contains 40000 tokens, too large for model with 32768 maximum context length"}"#;
+        let (url, requests) = mock_server(vec![
+            (400, too_long.to_string()),
+            (200, chat_reply("fits now")),
+            (400, too_long.to_string()),
+            (400, too_long.to_string()),
+        ]);
+        let client = MistralClient::new("key".to_string()).with_base_url(url);
+        let mut session = ChatSession::new(client);
+        for turn in 1..=4 {
+            push_turn(&mut session, &format!("question {} {}", turn, "padding ".repeat(50)), "answer");
+        }
+
+        let (reply, _) = session.send("latest").await.unwrap();
+        assert_eq!(reply, "fits now");
+        assert!(session.context_trimmed);
+        assert!(session.max_context_tokens.is_some());
+        let sent = |body: Vec<u8>| serde_json::from_slice::<serde_json::Value>(&body).unwrap()["messages"].as_array().unwrap().len();
+        let first = sent(requests.recv().unwrap().1);
+        let retry = sent(requests.recv().unwrap().1);
+        assert_eq!(first, 9);
+        assert!(retry < first, "{} then {}", retry, first);
+
+        // A second refusal is reported rather than retried again
+        let error = session.send("another").await.unwrap_err();
+        assert!(error.downcast_ref::<ApiError>().is_some_and(ApiError::is_context_length));
+        let _ = requests.recv().unwrap();
+        let _ = requests.recv().unwrap();
+        assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(session.last_user_message(), Some("latest"));
+    }
+
+    #[test]
+    fn images_show_their_alt_text_and_url() {
+        let markdown = "A diagram: ![ownership graph](https://example.com/graph.png)";
+        let rendered = render_plain(markdown, 80);
+        assert!(
+            rendered.contains("🖼 ownership graph (https://example.com/graph.png)"),
+            "{}",
+            rendered
+        );
+
+        let renderer = MarkdownRenderer::new(80).with_options(RenderOptions {
+            hyperlinks: true,
+            ..RenderOptions::default()
+        });
+        let rendered = renderer.render(markdown);
+        assert!(rendered.contains("\x1B]8;;https://example.com/graph.png\x1B\\"), "{:?}", rendered);
+        assert!(strip_ansi(&rendered).contains("ownership graph"), "{:?}", rendered);
+    }
+
+    #[test]
+    fn separators_number_the_turn_about_to_start() {
+        let mut session = offline_session();
+        push_turn(&mut session, "first", "reply");
+        let separator = SeparatorConfig::default();
+        let rule = strip_ansi(&separator.before_next_turn(&session, 30).unwrap());
+        assert_eq!(rule, format!("── #2 {}", "─".repeat(24)));
+        assert_eq!(textwrap::core::display_width(&rule), 30);
+
+        push_turn(&mut session, "second", "reply");
+        let rule = strip_ansi(&separator.before_next_turn(&session, 30).unwrap());
+        assert!(rule.starts_with("── #3 "), "{}", rule);
+
+        let plain = SeparatorConfig {
+            number: Some(false),
+            ..SeparatorConfig::default()
+        };
+        assert_eq!(strip_ansi(&plain.before_next_turn(&session, 10).unwrap()), "─".repeat(10));
+        let timed = SeparatorConfig {
+            timestamp: Some(true),
+            ..SeparatorConfig::default()
+        };
+        let rule = Regex::new(r"^── #3 · \d\d:\d\d ─+$").unwrap();
+        assert!(rule.is_match(&strip_ansi(&timed.before_next_turn(&session, 40).unwrap())));
+        let off = SeparatorConfig {
+            enabled: Some(false),
+            ..SeparatorConfig::default()
+        };
+        assert_eq!(off.before_next_turn(&session, 40), None);
+    }
+
+    #[test]
+    fn callouts_get_their_label_and_icon() {
+        let rendered = render_plain("> [!NOTE]\n> Read this first.\n\n> [!WARNING]\n> Mind the gap.", SNAPSHOT_WIDTH);
+        let lines: Vec<&str> = rendered.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        assert_eq!(lines, ["┃ ℹ Note", "┃ Read this first.", "┃ ⚠ Warning", "┃ Mind the gap."]);
+
+        // An ordinary quote isn't a callout
+        let rendered = render_plain("> Just a quote.", SNAPSHOT_WIDTH);
+        assert!(!rendered.contains("Note") && rendered.contains("Just a quote."), "{}", rendered);
+    }
+
+    #[test]
+    fn rewind_keeps_the_conversation_up_to_a_reply() {
+        let mut session = offline_session();
+        for turn in 1..=3 {
+            push_turn(&mut session, &format!("question {}", turn), &format!("answer {}", turn));
+        }
+
+        for index in [0, 1, 3, 7] {
+            assert!(session.rewind_to(index).is_err(), "message {} isn't a reply", index);
+        }
+        assert_eq!(session.messages.len(), 6);
+
+        assert_eq!(session.rewind_to(4).unwrap(), 2);
+        let contents: Vec<&str> = session.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["question 1", "answer 1", "question 2", "answer 2"]);
+        assert_eq!(session.rewind_to(4).unwrap(), 0);
+    }
+
+    #[test]
+    fn logit_bias_is_sent_only_when_set() {
+        let request = |logit_bias| ChatRequest {
+            model: "mistral-small".to_string(),
+            messages: vec![ChatMessage::now("user", "Hi".to_string())],
+            params: Params::default(),
+            logit_bias,
+            stream: false,
+        };
+
+        let json = serde_json::to_value(request(None)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"model": "mistral-small", "messages": [{"role": "user", "content": "Hi"}]})
+        );
+
+        let bias = HashMap::from([("1234".to_string(), -100.0), ("42".to_string(), 5.5)]);
+        let json = serde_json::to_value(request(Some(bias.clone()))).unwrap();
+        assert_eq!(json["logit_bias"], serde_json::json!({"1234": -100.0, "42": 5.5}));
+
+        assert!(MistralClient::new(String::new()).with_logit_bias(bias).unwrap().logit_bias.is_some());
+        assert!(MistralClient::new(String::new()).with_logit_bias(HashMap::new()).unwrap().logit_bias.is_none());
+        let bad_token = HashMap::from([("hello".to_string(), 1.0)]);
+        assert!(MistralClient::new(String::new()).with_logit_bias(bad_token).is_err());
+        let too_big = HashMap::from([("7".to_string(), 100.5)]);
+        assert!(MistralClient::new(String::new()).with_logit_bias(too_big).is_err());
+    }
+
+    #[test]
+    fn console_blocks_highlight_commands_but_not_output() {
+        let renderer = MarkdownRenderer::new(SNAPSHOT_WIDTH);
+        let theme = &renderer.theme_set.themes["base16-ocean.dark"];
+
+        let command = renderer.console_line("$ ls -la /tmp\n", theme);
+        assert!(command.ends_with("\x1b[0m\n"), "{:?}", command);
+        assert!(command.contains("\x1b[38;2;"), "the command is highlighted: {:?}", command);
+        assert_eq!(strip_ansi(&command), "$ ls -la /tmp\n");
+        assert_eq!(strip_ansi(&renderer.console_line("# apt install jq", theme)), "# apt install jq");
+
+        for output in ["total 8\n", "drwxrwxrwt  2 root root 4096 Jan  1 00:00 .\n", "$HOME is unset\n"] {
+            let line = renderer.console_line(output, theme);
+            assert!(!line.contains("\x1b[38;2;"), "output isn't highlighted: {:?}", line);
+            assert_eq!(strip_ansi(&line), output);
+        }
+
+        let rendered = render_plain("```console\n$ echo hi\nhi\n```", SNAPSHOT_WIDTH);
+        assert!(rendered.contains("$ echo hi") && rendered.contains("hi"), "{}", rendered);
+    }
+
+    #[test]
+    fn nothing_is_written_when_history_is_off() {
+        let dir = temp_dir("history-off");
+        let off = Config {
+            history: Some(false),
+            ..Config::default()
+        };
+        assert_eq!(history_file(&off, Some(dir.clone())), None);
+        assert!(session_store(&off, dir.join("sessions")).is_none());
+
+        let mut session = offline_session();
+        push_turn(&mut session, "question", "answer");
+        session.save().unwrap();
+        assert!(session.fork("copy").is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let on = Config::default();
+        assert_eq!(history_file(&on, Some(dir.clone())), Some(dir.join(".mistral_history")));
+        let mut session = offline_session().with_store(session_store(&on, dir.join("sessions")).unwrap());
+        push_turn(&mut session, "question", "answer");
+        session.save().unwrap();
+        assert!(dir.join("sessions").join(format!("{}.json", session.id)).exists());
+    }
+
+    #[test]
+    fn auth_headers_follow_the_scheme() {
+        let client = || MistralClient::new("sk-123".to_string());
+
+        let headers = client().auth_headers().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[AUTHORIZATION], "Bearer sk-123");
+
+        let headers = client()
+            .with_auth_scheme(AuthScheme::Header("x-api-key".to_string()))
+            .auth_headers()
+            .unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-api-key"], "sk-123");
+
+        let headers = client().with_auth_scheme(AuthScheme::None).auth_headers().unwrap();
+        assert!(headers.is_empty());
+
+        let error = client()
+            .with_auth_scheme(AuthScheme::Header("bad header".to_string()))
+            .auth_headers()
+            .unwrap_err();
+        assert!(error.to_string().contains("not a valid header name"), "{}", error);
+    }
+
+    #[test]
+    fn forks_are_saved_apart_from_the_original() {
+        let dir = temp_dir("fork");
+        let mut session = offline_session().with_store(Box::new(FileSessionStore::new(&dir)));
+        assert!(session.fork("too-early").is_err());
+
+        push_turn(&mut session, "question", "answer");
+        let original = session.id.clone();
+        session.fork("experiment").unwrap();
+        assert_eq!(session.id, "experiment");
+        let original_file = dir.join(format!("{}.json", original));
+        let saved_original = std::fs::read_to_string(&original_file).unwrap();
+        assert!(dir.join("experiment.json").exists());
+
+        push_turn(&mut session, "follow-up", "more");
+        session.save().unwrap();
+        assert_eq!(std::fs::read_to_string(&original_file).unwrap(), saved_original);
+        let store = FileSessionStore::new(&dir);
+        assert_eq!(store.load(&original).unwrap().messages.len(), 2);
+        assert_eq!(store.load("experiment").unwrap().messages.len(), 4);
+
+        let error = session.fork("experiment").unwrap_err();
+        assert!(error.to_string().contains("already saved"), "{}", error);
+        assert!(session.fork("bad name").is_err());
+        assert_eq!(session.id, "experiment");
+    }
+
+    #[test]
+    fn few_shot_path_is_relative_to_the_config_file() {
+        let dir = temp_dir("few-shot-path");
+        let config = dir.join(PROJECT_CONFIG_FILE);
+        std::fs::write(&config, "few_shot = \"examples.json\"\n").unwrap();
+        let table = Config::read_table(&config).unwrap();
+        assert_eq!(
+            table["few_shot"].as_str().map(PathBuf::from),
+            Some(dir.join("examples.json"))
+        );
+
+        std::fs::write(&config, "few_shot = \"/etc/examples.json\"\n").unwrap();
+        let table = Config::read_table(&config).unwrap();
+        assert_eq!(table["few_shot"].as_str(), Some("/etc/examples.json"));
+    }
+
+    #[test]
+    fn examples_follow_the_system_prompt_in_order() {
+        let dir = temp_dir("examples");
+        let path = dir.join("examples.json");
+        std::fs::write(
+            &path,
+            r#"[
+                {"role": "user", "content": "Convert 3 km to miles"},
+                {"role": "assistant", "content": "1.86 mi"},
+                {"role": "user", "content": "Convert 10 kg to pounds"},
+                {"role": "assistant", "content": "22.05 lb"}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut session = offline_session();
+        session.system_prompt = Some("Be brief.".to_string());
+        session.examples = load_examples(&path).unwrap();
+        session.messages.push(ChatMessage::now("user", "Convert 5 m to feet".to_string()));
+
+        let contents: Vec<String> = session
+            .request_messages()
+            .iter()
+            .map(|msg| format!("{}: {}", msg.role, msg.content))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                "system: Be brief.",
+                "user: Convert 3 km to miles",
+                "assistant: 1.86 mi",
+                "user: Convert 10 kg to pounds",
+                "assistant: 22.05 lb",
+                "user: Convert 5 m to feet",
+            ]
+        );
+    }
+
+    #[test]
+    fn examples_must_alternate_and_end_with_an_answer() {
+        let dir = temp_dir("bad-examples");
+        let path = dir.join("examples.json");
+        std::fs::write(&path, r#"[{"role": "assistant", "content": "hi"}]"#).unwrap();
+        assert!(load_examples(&path).is_err());
+        std::fs::write(&path, r#"[{"role": "user", "content": "hi"}]"#).unwrap();
+        assert!(load_examples(&path).is_err());
+    }
+
+    #[test]
+    fn source_is_the_stored_reply_byte_for_byte() {
+        let mut session = offline_session();
+        let content = "# Title\n\n**bold** and `code`\n\n```rust\nfn main() {}\n```\n| a | b |\n|---|---|\n";
+        push_turn(&mut session, "question", content);
+
+        let mut output = Vec::new();
+        write_source(&mut output, session.reply(2).unwrap()).unwrap();
+        assert_eq!(output, content.as_bytes());
+        assert!(session.reply(1).is_err());
+    }
+
+    #[test]
+    fn large_bodies_are_gzipped_when_compression_is_on() {
+        use std::io::Read;
+
+        let small = vec![b'a'; COMPRESS_ABOVE_BYTES];
+        assert_eq!(encode_body(small.clone(), true).unwrap(), (small, None));
+        let large = b"{\"content\": \"pasted file\"}".repeat(100);
+        assert!(large.len() > COMPRESS_ABOVE_BYTES);
+        assert_eq!(encode_body(large.clone(), false).unwrap(), (large.clone(), None));
+
+        let (body, encoding) = encode_body(large.clone(), true).unwrap();
+        assert_eq!(encoding, Some("gzip"));
+        assert!(body.len() < large.len());
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(body.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, large);
+    }
+
+    #[tokio::test]
+    async fn compressed_requests_say_so_in_a_header() {
+        let (url, requests) = mock_server(vec![(200, chat_reply("ok")), (200, chat_reply("ok"))]);
+        let client = MistralClient::new("key".to_string()).with_base_url(url).with_request_compression();
+
+        let long = vec![ChatMessage::now("user", "word ".repeat(COMPRESS_ABOVE_BYTES))];
+        client.send_message(long).await.unwrap();
+        let (head, _) = requests.recv().unwrap();
+        assert!(head.to_lowercase().contains("content-encoding: gzip"), "{}", head);
+
+        let short = vec![ChatMessage::now("user", "Hi".to_string())];
+        client.send_message(short).await.unwrap();
+        let (head, body) = requests.recv().unwrap();
+        assert!(!head.to_lowercase().contains("content-encoding"), "{}", head);
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_ok());
+    }
+
+    #[test]
+    fn turn_metrics_carry_usage_and_cost() {
+        let mut prices = HashMap::new();
+        prices.insert("mistral-small".to_string(), Price { input: 0.2, output: 0.6 });
+        let sink = MetricsSink::new(&MetricsConfig {
+            webhook: None,
+            statsd: Some("127.0.0.1:8125".to_string()),
+            prices,
+        })
+        .unwrap();
+        let usage = Usage { prompt_tokens: 1500, completion_tokens: 500 };
+
+        let metric = sink.turn("mistral-small", Duration::from_millis(1234), Some(usage));
+        let cost = metric.cost.unwrap();
+        assert!((cost - 0.0006).abs() < 1e-12, "{}", cost);
+        assert_eq!(
+            serde_json::to_value(&metric).unwrap(),
+            serde_json::json!({
+                "model": "mistral-small",
+                "latency_ms": 1234,
+                "prompt_tokens": 1500,
+                "completion_tokens": 500,
+                "cost": cost,
+            })
+        );
+        assert_eq!(
+            metric.statsd_lines(),
+            "mistral_small.mistral-small.latency:1234|ms\n\
+             mistral_small.mistral-small.prompt_tokens:1500|c\n\
+             mistral_small.mistral-small.completion_tokens:500|c\n\
+             mistral_small.mistral-small.cost_microdollars:600|c"
+        );
+
+        // No price, or no usage, means no cost
+        let metric = sink.turn("open.mixtral", Duration::from_millis(5), Some(usage));
+        assert_eq!(metric.cost, None);
+        assert_eq!(metric.statsd_lines().lines().count(), 3);
+        assert!(metric.statsd_lines().starts_with("mistral_small.open_mixtral.latency:5|ms"));
+        let metric = sink.turn("mistral-small", Duration::from_millis(5), None);
+        assert_eq!((metric.prompt_tokens, metric.cost), (None, None));
+        assert!(MetricsSink::new(&MetricsConfig::default()).is_none());
+    }
+
+    #[tokio::test]
+    async fn message_times_are_saved_but_never_sent() {
+        let (url, requests) = mock_server(vec![(200, chat_reply("first")), (200, chat_reply("second"))]);
+        let dir = temp_dir("created-at");
+        let client = MistralClient::new("key".to_string()).with_base_url(url);
+        let mut session = ChatSession::new(client).with_store(Box::new(FileSessionStore::new(&dir)));
+
+        let before = chrono::Local::now();
+        session.send("one").await.unwrap();
+        session.send("two").await.unwrap();
+        assert!(session
+            .messages
+            .iter()
+            .all(|msg| msg.created_at.is_some_and(|time| time >= before)));
+
+        // The second request carries the first turn, without its times
+        let _ = requests.recv().unwrap();
+        let (_, body) = requests.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([
+                {"role": "user", "content": "one"},
+                {"role": "assistant", "content": "first"},
+                {"role": "user", "content": "two"},
+            ])
+        );
+
+        session.save().unwrap();
+        let saved = std::fs::read_to_string(dir.join(format!("{}.json", session.id))).unwrap();
+        assert_eq!(saved.matches("created_at").count(), 4);
+        let mut loaded = offline_session().with_store(Box::new(FileSessionStore::new(&dir)));
+        loaded.load(&session.id).unwrap();
+        let times = |session: &ChatSession| session.messages.iter().map(|msg| msg.created_at).collect::<Vec<_>>();
+        assert_eq!(times(&loaded), times(&session));
+
+        // Conversations saved before times were kept still load
+        std::fs::write(dir.join("old.json"), r#"{"id": "old", "messages": [{"role": "user", "content": "hi"}]}"#).unwrap();
+        loaded.load("old").unwrap();
+        assert_eq!(loaded.messages[0].created_at, None);
+    }
+
+    #[test]
+    fn unfenced_code_is_fenced_and_everything_else_left_alone() {
+        let code = "Here is the function:\n\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nCall it with two numbers.";
+        assert_eq!(
+            fence_unfenced_code(code),
+            "Here is the function:\n\n```\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n```\n\nCall it with two numbers."
+        );
+
+        let prose = "Ownership means each value has one owner.\nWhen the owner goes out of scope, the value is dropped.\n";
+        assert!(matches!(fence_unfenced_code(prose), Cow::Borrowed(_)));
+
+        let fenced = "Like this:\n\n```rust\nlet x = 5;\nlet y = x + 1;\n```\n";
+        assert!(matches!(fence_unfenced_code(fenced), Cow::Borrowed(_)));
+
+        let list = "1. Install Rust.\n2. Run `cargo new demo`.\n3. Run `cargo run` in the new directory.\n";
+        assert!(matches!(fence_unfenced_code(list), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn refusal_detector_spots_declined_replies() {
+        let detector = RefusalDetector::new(&RefusalConfig {
+            action: RefusalAction::Badge,
+            patterns: None,
+        })
+        .unwrap();
+        for reply in [
+            "I'm sorry, but I can't help with that.",
+            "I apologize, but I cannot assist with this request.",
+            "Sorry, I can't do that.",
+            "I won't provide instructions for that.",
+            "I am unable to comply with this request.",
+            "I must decline this request.",
+            "As an AI language model, I cannot browse the internet.",
+            "  i can't help with that",
+        ] {
+            assert!(detector.declined(reply), "{:?} should be declined", reply);
+        }
+        for reply in [
+            "Sure! Here is the function you asked for.",
+            "Rust can't have null pointers in safe code.",
+            "Here's the answer.\n\nI can't help noticing the loop never ends.",
+        ] {
+            assert!(!detector.declined(reply), "{:?} should not be declined", reply);
+        }
+
+        let off = RefusalDetector::new(&RefusalConfig::default()).unwrap();
+        assert!(!off.declined("I can't help with that."));
+        assert!(!off.regenerates() && !detector.regenerates());
+    }
+
+    #[test]
+    fn code_blocks_are_picked_by_number() {
+        let reply = "First:\n\n```python\nprint(1)\n```\n\nThen:\n\n```\necho two\n```\n";
+        let block = code_block(reply, 1).unwrap();
+        assert_eq!((block.language.as_deref(), block.code.as_str()), (Some("python"), "print(1)\n"));
+        assert_eq!(code_block(reply, 2).unwrap().code, "echo two\n");
+
+        for index in [0, 3] {
+            let error = code_block(reply, index).unwrap_err();
+            assert_eq!(error.to_string(), format!("There is no code block {}; the last reply has 2", index));
+        }
+        let error = code_block("No code here.", 1).unwrap_err();
+        assert_eq!(error.to_string(), "The last reply has no code blocks");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn piped_code_reaches_the_command() {
+        let code = "line one\nline two\n".repeat(10_000);
+        let output = pipe_code(&code, "cat").await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, code.as_bytes());
+
+        let output = pipe_code("ignored", "echo oops >&2; exit 3").await.unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stderr, b"oops\n");
+    }
+
+    #[test]
+    fn api_key_is_required_unless_auth_is_none_for_another_server() {
+        let bearer = Config::default();
+        assert_eq!(resolve_api_key(Some("sk-1".to_string()), &bearer).unwrap(), "sk-1");
+        let error = resolve_api_key(None, &bearer).unwrap_err();
+        assert!(error.to_string().contains("MISTRAL_API_KEY must be set"), "{}", error);
+
+        let header = Config {
+            auth: Some(AuthScheme::Header("x-api-key".to_string())),
+            ..Config::default()
+        };
+        assert!(resolve_api_key(None, &header).is_err());
+
+        let local = Config {
+            auth: Some(AuthScheme::None),
+            base_url: Some("http://localhost:8080".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(resolve_api_key(None, &local).unwrap(), "");
+        assert_eq!(resolve_api_key(Some("sk-2".to_string()), &local).unwrap(), "sk-2");
+
+        // The Mistral API itself always needs a key, whatever auth says
+        for base_url in [None, Some(DEFAULT_BASE_URL.to_string()), Some(format!("{}/", DEFAULT_BASE_URL))] {
+            let none = Config {
+                auth: Some(AuthScheme::None),
+                base_url,
+                ..Config::default()
+            };
+            let error = resolve_api_key(None, &none).unwrap_err();
+            assert!(error.to_string().contains("MISTRAL_API_KEY must be set"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn long_words_are_broken_to_fit_the_width() {
+        let token: String = "abcdefghij".repeat(50);
+        let rendered = render_plain(&format!("The key is {} and nothing else.", token), 80);
+        let lines: Vec<&str> = rendered.lines().filter(|line| !line.trim().is_empty()).collect();
+        assert!(lines.len() > 6, "{}", rendered);
+        assert!(lines.iter().all(|line| textwrap::core::display_width(line) <= 80), "{}", rendered);
+
+        // The token starts on a line of its own, and each line it is
+        // broken at is marked
+        assert_eq!(lines[0].trim(), "The key is");
+        let (last, broken) = lines[1..].split_last().unwrap();
+        assert!(broken.iter().all(|line| line.ends_with(CONTINUATION_MARKER)), "{}", rendered);
+        assert!(last.ends_with("and nothing else."), "{}", rendered);
+        let rejoined: String = rendered
+            .split_whitespace()
+            .collect::<String>()
+            .replace(CONTINUATION_MARKER, "");
+        assert!(rejoined.contains(&token));
+    }
+
+    #[test]
+    fn table_cells_are_measured_in_display_columns() {
+        let mut table = Table::new(vec![("Word".to_string(), None), ("Meaning".to_string(), None)]);
+        table.add_row(vec!["漢字漢字漢字漢字漢字漢字".to_string(), "Chinese characters".to_string()]);
+        table.calculate_column_widths(40);
+        let rendered = table.render();
+        let widths: Vec<usize> = rendered
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(textwrap::core::display_width)
+            .collect();
+        assert!(widths.iter().all(|&width| width == widths[0] && width <= 40), "{}", rendered);
+    }
+
+    #[test]
+    fn banner_renders_its_text_and_knows_when_to_repeat() {
+        let renderer = MarkdownRenderer::new(SNAPSHOT_WIDTH);
+        let banner: BannerConfig = toml::from_str("text = \"**Internal** use only\"\ncolor = \"bright red\"").unwrap();
+        let rendered = banner.render(&renderer).unwrap();
+        assert_eq!(strip_ansi(&rendered).trim(), "Internal use only");
+        assert!(!rendered.ends_with('\n'));
+        assert!(!banner.every_turn(), "shown once unless configured");
+
+        let banner: BannerConfig = toml::from_str("text = \"Hi\"\nfrequency = \"once\"").unwrap();
+        assert!(!banner.every_turn());
+        let banner: BannerConfig = toml::from_str("text = \"Hi\"\nfrequency = \"every-turn\"").unwrap();
+        assert!(banner.every_turn());
+        assert!(toml::from_str::<BannerConfig>("frequency = \"sometimes\"").is_err());
+
+        for text in ["", "  \n "] {
+            let banner = BannerConfig {
+                text: Some(text.to_string()),
+                ..BannerConfig::default()
+            };
+            assert_eq!(banner.render(&renderer), None);
+        }
+        assert_eq!(BannerConfig::default().render(&renderer), None);
+    }
+
+    #[test]
+    fn latest_session_is_the_most_recently_modified_file() {
+        let dir = temp_dir("latest");
+        let store = FileSessionStore::new(&dir);
+        assert_eq!(store.latest().unwrap(), None);
+
+        let base = SystemTime::now() - Duration::from_secs(3600);
+        for (name, minutes) in [("older", 10), ("newest", 30), ("oldest", 0), ("not a-valid id", 50)] {
+            let path = dir.join(format!("{}.json", name));
+            std::fs::write(&path, "{\"id\":\"x\",\"messages\":[]}").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(base + Duration::from_secs(minutes * 60)).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "not a session").unwrap();
+
+        assert_eq!(store.latest().unwrap().as_deref(), Some("newest"));
+    }
+
+    #[test]
+    fn continuing_without_a_store_starts_fresh() {
+        let mut session = offline_session();
+        assert!(!session.load_latest().unwrap());
+        assert!(session.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn complete_posts_a_flat_prompt() {
+        let reply = serde_json::json!({"choices": [{"text": " world\r\nand more"}]}).to_string();
+        let (url, requests) = mock_server(vec![(200, reply), (200, r#"{"choices": []}"#.to_string())]);
+        let mut client = MistralClient::new("key".to_string())
+            .with_base_url(url)
+            .with_completions_path("/custom/complete")
+            .with_model("local-model");
+        client.params.temperature = Some(0.5);
+
+        assert_eq!(client.complete("Hello").await.unwrap(), " world\nand more");
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /custom/complete HTTP/1.1"), "{}", head);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"model": "local-model", "prompt": "Hello", "temperature": 0.5})
+        );
+
+        let error = client.complete("Hello").await.unwrap_err();
+        assert!(error.to_string().contains("no choices"), "{}", error);
+    }
+
+    #[test]
+    fn max_messages_bounds_history_over_many_turns() {
+        let mut session = offline_session();
+        session.max_messages = Some(6);
+        session.messages.push(ChatMessage::now("system", "pinned".to_string()));
+        for i in 0..100 {
+            push_turn(&mut session, &format!("q{}", i), &format!("a{}", i));
+            session.enforce_max_messages();
+            assert!(session.messages.len() <= 7, "{} messages after turn {}", session.messages.len(), i);
+        }
+        let contents: Vec<&str> = session.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["pinned", "q97", "a97", "q98", "a98", "q99", "a99"]);
+    }
+
+    #[test]
+    fn max_messages_trims_a_resumed_conversation_without_cutting_its_file() {
+        let dir = temp_dir("max-messages-load");
+        let mut long = offline_session().with_store(Box::new(FileSessionStore::new(&dir)));
+        long.messages.push(ChatMessage::now("system", "pinned".to_string()));
+        for i in 0..10 {
+            push_turn(&mut long, &format!("q{}", i), &format!("a{}", i));
+        }
+        long.save().unwrap();
+
+        let mut session = offline_session().with_store(Box::new(FileSessionStore::new(&dir)));
+        session.max_messages = Some(4);
+        session.load(&long.id).unwrap();
+        assert_eq!(session.messages.len(), 21);
+
+        push_turn(&mut session, "new question", "new answer");
+        session.enforce_max_messages();
+        let contents: Vec<&str> = session.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["pinned", "q9", "a9", "new question", "new answer"]);
+        assert_ne!(session.id, long.id, "the trimmed conversation is saved apart");
+        session.save().unwrap();
+        let saved_id = session.id.clone();
+
+        // Later cuts stay in the new conversation
+        push_turn(&mut session, "another", "answer");
+        session.enforce_max_messages();
+        assert_eq!(session.id, saved_id);
+        assert_eq!(session.messages.len(), 5);
+
+        // The latest turn is kept even when it alone is over the cap
+        session.max_messages = Some(1);
+        session.enforce_max_messages();
+        let contents: Vec<&str> = session.messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["pinned", "another", "answer"]);
+
+        let store = FileSessionStore::new(&dir);
+        assert_eq!(store.load(&long.id).unwrap().messages.len(), 21, "the resumed file keeps its history");
+        assert_eq!(store.load(&saved_id).unwrap().messages.len(), 5);
+    }
+
+    #[test]
+    fn scripts_become_unicode_where_possible() {
+        assert_eq!(render_scripts("H~2~O"), "H₂O");
+        assert_eq!(render_scripts("E=mc^2^"), "E=mc²");
+        assert_eq!(render_scripts("x^(n+1)^ and a~i~"), "x⁽ⁿ⁺¹⁾ and aᵢ");
+        assert_eq!(render_scripts("from ~10 to 20~ units"), "from ~10 to 20~ units");
+        assert_eq!(render_scripts("2^Q^ stays"), "2^Q^ stays");
+        assert!(matches!(render_scripts("nothing to do"), Cow::Borrowed(_)));
+
+        // Strikethrough isn't a pair of subscripts
+        assert_eq!(render_scripts("~~strike~~"), "~~strike~~");
+        assert_eq!(render_scripts("it was ~~hot~~, H~2~O and ~~~ too"), "it was ~~hot~~, H₂O and ~~~ too");
+
+        // Only when turned on, and code is shown as written
+        let markdown = "Water is H~2~O, written `H~2~O`, not ~~strike~~.";
+        let renderer = MarkdownRenderer::new(SNAPSHOT_WIDTH).with_options(RenderOptions {
+            unicode_scripts: true,
+            ..RenderOptions::default()
+        });
+        let rendered = strip_ansi(&renderer.render(markdown));
+        assert!(rendered.contains("Water is H₂O, written `H~2~O`, not ~~strike~~."), "{}", rendered);
+        let rendered = render_plain(markdown, SNAPSHOT_WIDTH);
+        assert!(rendered.contains("Water is H~2~O, written `H~2~O`, not ~~strike~~."), "{}", rendered);
+    }
+
+    #[test]
+    fn set_width_changes_the_wrapping_of_the_next_render() {
+        let paragraph = "The quick brown fox jumps over the lazy dog and keeps running across the field.";
+        let mut renderer = MarkdownRenderer::new(100);
+        let wide = strip_ansi(&renderer.render(paragraph));
+        assert_eq!(wide.trim_end().lines().count(), 1);
+
+        renderer.set_width(40);
+        let narrow = strip_ansi(&renderer.render(paragraph));
+        assert!(narrow.trim_end().lines().count() > 1);
+        assert!(narrow.lines().all(|line| textwrap::core::display_width(line) <= 40), "{}", narrow);
+    }
+
+    #[test]
+    fn sse_decoder_is_lenient_about_framing() {
+        let mut decoder = SseDecoder::new(&["END".to_string()]);
+        let body = concat!(
+            ": connected\n",
+            "event: message\n",
+            "id: 1\n",
+            "retry: 1000\n",
+            "data: {\"a\":1}\n",
+            "\n",
+            "data:{\"b\":2}\r\n",
+            "\r\n",
+            "{\"c\":3}\n",
+            "   \n",
+            "keep-alive\n",
+            "data: [DONE]\n",
+            "[DONE]\n",
+            "END\n",
+            "ENDING\n",
+        );
+        assert_eq!(
+            decoder.push(body.as_bytes()),
+            ["{\"a\":1}", "{\"b\":2}", "{\"c\":3}", "[DONE]", "[DONE]", "END"]
+        );
+
+        // Network chunks can end anywhere, even inside a line
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"content\":").is_empty());
+        assert!(decoder.push(b" \"hel").is_empty());
+        assert_eq!(decoder.push(b"lo\"}\r\ndata: {\"x\""), ["{\"content\": \"hello\"}"]);
+        assert_eq!(decoder.push(b":0}\n"), ["{\"x\":0}"]);
+
+        // Without being configured, a bare custom marker is skipped
+        assert!(decoder.push(b"END\n").is_empty());
+    }
+
+    #[test]
+    fn no_wrap_keeps_the_reply_line_breaks() {
+        colored::control::set_override(false);
+        let paragraph = "The first line of a paragraph that goes on for quite a while\nand a second line that the reply broke on its own.";
+        let render = |no_wrap| {
+            let renderer = MarkdownRenderer::new(40).with_options(RenderOptions {
+                no_wrap,
+                ..RenderOptions::default()
+            });
+            strip_ansi(&renderer.render(paragraph))
+        };
+
+        let wrapped = render(false);
+        let lines: Vec<&str> = wrapped.lines().filter(|line| !line.trim().is_empty()).collect();
+        assert!(lines.len() > 2, "{}", wrapped);
+        assert!(lines.iter().all(|line| textwrap::core::display_width(line) <= 40), "{}", wrapped);
+        assert!(!wrapped.contains("a while\n"), "soft breaks are joined: {}", wrapped);
+
+        let unwrapped = render(true);
+        let lines: Vec<&str> = unwrapped.lines().filter(|line| !line.trim().is_empty()).collect();
+        assert_eq!(
+            lines.iter().map(|line| line.trim()).collect::<Vec<_>>(),
+            [
+                "The first line of a paragraph that goes on for quite a while",
+                "and a second line that the reply broke on its own.",
+            ],
+            "{}",
+            unwrapped
+        );
+    }
+
+    #[test]
+    fn ran_follow_up_quotes_the_code_and_status() {
+        assert_eq!(fence_for("plain"), "```");
+        assert_eq!(fence_for("has ``` inside"), "````");
+        assert_eq!(fence_for("has ````` inside"), "``````");
+
+        let block = CodeBlock {
+            language: Some("sh".to_string()),
+            code: "ls -la\n".to_string(),
+        };
+        assert_eq!(
+            ran_follow_up(&block, 2, "  exit status 0\n"),
+            "I ran code block 2:\n\n```sh\nls -la\n```\n\nand got: exit status 0"
+        );
+
+        let long_status = "x".repeat(RAN_INLINE_STATUS + 1);
+        assert_eq!(
+            ran_follow_up(&block, 1, &long_status),
+            format!("I ran code block 1:\n\n```sh\nls -la\n```\n\nand got:\n\n```\n{}\n```", long_status)
+        );
+        let at_limit = "y".repeat(RAN_INLINE_STATUS);
+        assert!(ran_follow_up(&block, 1, &at_limit).ends_with(&format!("and got: {}", at_limit)));
+        assert!(ran_follow_up(&block, 1, "line one\nline two").ends_with("and got:\n\n```\nline one\nline two\n```"));
+
+        // Code holding a fence of its own gets a longer one, so the model
+        // reads it back as a single block
+        let markdown = CodeBlock {
+            language: Some("markdown".to_string()),
+            code: "Example:\n\n```rust\nfn main() {}\n```".to_string(),
+        };
+        let message = ran_follow_up(&markdown, 1, "rendered fine");
+        assert!(message.starts_with("I ran code block 1:\n\n````markdown\nExample:"), "{}", message);
+        let blocks = extract_code_blocks(&message);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code.trim_end(), markdown.code);
+    }
+
+    /// Renders each `fixtures/render/*.md` and compares it with the `.txt`
+    /// next to it. Run with `UPDATE_SNAPSHOTS=1` to rewrite the `.txt` files
+    /// after an intended change, then review the diff.
+    #[test]
+    fn rendering_matches_snapshots() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/render");
+        let update = env::var_os("UPDATE_SNAPSHOTS").is_some();
+        let mut fixtures: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "md"))
+            .collect();
+        fixtures.sort();
+        assert!(fixtures.len() >= 10, "expected at least ten fixtures in {}", dir.display());
+
+        let mut mismatched = Vec::new();
+        for fixture in &fixtures {
+            let rendered = render_plain(&std::fs::read_to_string(fixture).unwrap(), SNAPSHOT_WIDTH);
+            let snapshot = fixture.with_extension("txt");
+            if update {
+                std::fs::write(&snapshot, &rendered).unwrap();
+            } else if std::fs::read_to_string(&snapshot).ok().as_deref() != Some(rendered.as_str()) {
+                eprintln!("--- {} rendered as:\n{}", fixture.display(), rendered);
+                mismatched.push(fixture.file_name().unwrap().to_string_lossy().into_owned());
+            }
+        }
+        assert!(mismatched.is_empty(), "rendering changed for {:?}", mismatched);
+    }
+}
@@ -0,0 +1,340 @@
+//! Full-screen chat interface, built with the `tui` feature and started
+//! with `--tui`. The conversation scrolls in its own pane above an input
+//! box, so replies never have to be cleared off the screen.
+
+use std::time::Duration;
+
+use ansi_to_tui::IntoText;
+use anyhow::Result;
+use colored::*;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::text::Text;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::{
+    build_renderer, context_trimmed_notice, ChatSession, Config, ErrorFormatter, PrefixFilter, RefusalDetector, SystemPromptFile,
+    REFUSAL_BADGE, TYPEAHEAD_NOTICE,
+};
+
+/// Lines moved by Page Up and Page Down.
+const PAGE: usize = 10;
+
+/// What the TUI shows, kept apart from the terminal so a turn can be
+/// applied to it without drawing anything.
+#[derive(Debug)]
+struct TuiState {
+    /// Columns available for text inside the conversation pane
+    width: usize,
+    /// Each message, error or notice as ANSI-styled text, oldest first
+    entries: Vec<String>,
+    input: String,
+    /// Lines scrolled back from the end of the conversation
+    scroll_back: usize,
+    /// Set while waiting for a reply
+    busy: bool,
+}
+
+impl TuiState {
+    fn new(width: usize) -> Self {
+        Self {
+            width,
+            entries: Vec::new(),
+            input: String::new(),
+            scroll_back: 0,
+            busy: false,
+        }
+    }
+
+    /// `text` wrapped to the pane. Replies arrive already wrapped by the
+    /// renderer.
+    fn wrapped(&self, text: &str, indent: &str) -> String {
+        let options = textwrap::Options::new(self.width).subsequent_indent(indent);
+        text.lines()
+            .flat_map(|line| textwrap::wrap(line, &options))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn push_user(&mut self, input: &str) {
+        let text = self.wrapped(&format!("> {}", input), "  ");
+        let (prompt, rest) = text.split_at(2);
+        self.push(format!("{}{}", prompt.blue().bold(), rest));
+    }
+
+    fn push_reply(&mut self, rendered: &str) {
+        self.push(rendered.cyan().to_string());
+    }
+
+    fn push_notice(&mut self, notice: &str) {
+        self.push(self.wrapped(notice, "").yellow().to_string());
+    }
+
+    /// Adds an entry and scrolls back down so it is visible.
+    fn push(&mut self, entry: String) {
+        self.entries.push(entry);
+        self.scroll_back = 0;
+    }
+
+    /// Empties the input box, returning what was typed unless it was blank.
+    fn take_input(&mut self) -> Option<String> {
+        let input = std::mem::take(&mut self.input);
+        let input = input.trim();
+        (!input.is_empty()).then(|| input.to_string())
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        self.scroll_back = self.scroll_back.saturating_add(lines);
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll_back = self.scroll_back.saturating_sub(lines);
+    }
+
+    /// The whole conversation as styled lines, one blank line between entries.
+    fn conversation(&self) -> Text<'static> {
+        let ansi = self.entries.join("\n\n");
+        ansi.into_text().unwrap_or_else(|_| Text::raw(ansi))
+    }
+}
+
+fn draw(frame: &mut Frame, state: &mut TuiState) {
+    let [conversation_area, input_area] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+
+    // The renderer has already wrapped the text to fit, so one line of the
+    // Text is one line on screen
+    let conversation = state.conversation();
+    let height = conversation_area.height.saturating_sub(2) as usize;
+    let last_top = conversation.lines.len().saturating_sub(height);
+    state.scroll_back = state.scroll_back.min(last_top);
+    let top = (last_top - state.scroll_back).min(u16::MAX as usize) as u16;
+
+    let title = if state.scroll_back > 0 {
+        format!(" Mistral Chat (scrolled back {} lines) ", state.scroll_back)
+    } else {
+        " Mistral Chat ".to_string()
+    };
+    frame.render_widget(
+        Paragraph::new(conversation).block(Block::bordered().title(title)).scroll((top, 0)),
+        conversation_area,
+    );
+
+    let hint = if state.busy {
+        " Thinking... "
+    } else {
+        " Enter to send, Esc to quit, PgUp/PgDn to scroll "
+    };
+    frame.render_widget(
+        Paragraph::new(state.input.as_str()).block(Block::bordered().title(hint)),
+        input_area,
+    );
+    let cursor = input_area.x + 1 + state.input.chars().count().min(u16::MAX as usize) as u16;
+    frame.set_cursor_position(Position::new(cursor, input_area.y + 1));
+}
+
+/// Drops key presses made while a reply was being generated, as the line
+/// UI does. Returns whether there were any.
+fn discard_pending_keys() -> Result<bool> {
+    let mut discarded = false;
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(key) = event::read()? {
+            discarded |= key.kind == KeyEventKind::Press;
+        }
+    }
+    Ok(discarded)
+}
+
+/// Runs the chat in the full-screen interface until the user quits.
+pub(crate) async fn run(
+    session: ChatSession,
+    config: &Config,
+    system_file: Option<SystemPromptFile>,
+    notice: Option<String>,
+) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, session, config, system_file, notice).await;
+    ratatui::restore();
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    mut session: ChatSession,
+    config: &Config,
+    mut system_file: Option<SystemPromptFile>,
+    notice: Option<String>,
+) -> Result<()> {
+    // Leave room for the pane's borders
+    let width = terminal.size()?.width.saturating_sub(4).max(20) as usize;
+    let mut renderer = build_renderer(config, width);
+    // ratatui draws escape sequences as text, so links stay plain here
+    renderer.options.hyperlinks = false;
+    let errors = ErrorFormatter::new(&config.errors, width);
+    let prefixes = PrefixFilter::new(config.strip_prefixes.as_deref().unwrap_or_default())?;
+    let refusals = RefusalDetector::new(&config.refusals)?;
+    let mut state = TuiState::new(width);
+    let banner = config.banner.render(&renderer);
+    if let Some(banner) = &banner {
+        state.push(banner.clone());
+    }
+    state.push_notice("Type a message and press Enter. `new` starts a fresh conversation, `exit` quits.");
+    if let Some(notice) = notice {
+        state.push(notice);
+    }
+
+    loop {
+        terminal.draw(|frame| draw(frame, &mut state))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Esc => break,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Char(c) => state.input.push(c),
+            KeyCode::Backspace => {
+                state.input.pop();
+            }
+            KeyCode::Up => state.scroll_up(1),
+            KeyCode::Down => state.scroll_down(1),
+            KeyCode::PageUp => state.scroll_up(PAGE),
+            KeyCode::PageDown => state.scroll_down(PAGE),
+            KeyCode::Enter => {
+                let Some(input) = state.take_input() else {
+                    continue;
+                };
+                if input.eq_ignore_ascii_case("exit") {
+                    break;
+                } else if input.eq_ignore_ascii_case("new") {
+                    session.clear();
+                    state.entries.clear();
+                    if let Some(banner) = &banner {
+                        state.push(banner.clone());
+                    }
+                    state.push_notice("Starting a fresh conversation...");
+                    continue;
+                } else if input.starts_with('/') {
+                    state.push_notice("Chat commands are only available without --tui");
+                    continue;
+                }
+
+                if let Some(file) = system_file.as_mut() {
+                    match file.reload_if_changed() {
+                        Ok(Some(prompt)) => {
+                            session.system_prompt = Some(prompt);
+                            state.push_notice(&format!("Reloaded the system prompt from {}", file.path.display()));
+                        }
+                        Ok(None) => {}
+                        Err(e) => state.push(errors.format(&e)),
+                    }
+                }
+
+                if let Some(banner) = banner.as_ref().filter(|_| config.banner.every_turn()) {
+                    state.push(banner.clone());
+                }
+                state.push_user(&input);
+                state.busy = true;
+                terminal.draw(|frame| draw(frame, &mut state))?;
+
+                let result = session.send(&input).await;
+                state.busy = false;
+                match result {
+                    Ok((mut response, _)) => {
+                        let mut regenerate_error = None;
+                        if refusals.regenerates() && refusals.declined(&response) {
+                            match session.regenerate_last(None).await {
+                                Ok(again) => response = again,
+                                Err(e) => regenerate_error = Some(e),
+                            }
+                        }
+                        let language_hint = session.language_hint(&input);
+                        state.push_reply(&renderer.render_with_hint(&prefixes.strip(&response), language_hint.as_deref()));
+                        if refusals.declined(&response) {
+                            state.push(REFUSAL_BADGE.dimmed().to_string());
+                        }
+                        if let Some(e) = regenerate_error {
+                            state.push(errors.format(&e));
+                        }
+                        if let Some(notice) = context_trimmed_notice(&session) {
+                            state.push_notice(&notice);
+                        }
+                        if let Err(e) = session.save() {
+                            state.push_notice(&format!("Warning: could not save the conversation: {}", e));
+                        }
+                    }
+                    Err(e) => state.push(errors.format(&e)),
+                }
+                if discard_pending_keys()? {
+                    state.push_notice(TYPEAHEAD_NOTICE);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    /// The conversation pane as plain lines.
+    fn plain_lines(state: &TuiState) -> Vec<String> {
+        state
+            .conversation()
+            .lines
+            .iter()
+            .map(|line| line.spans.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn turns_are_added_to_the_conversation() {
+        let mut state = TuiState::new(20);
+        state.push_user("a question long enough to wrap");
+        state.push_reply("The answer.");
+        state.push_notice("Saved");
+        assert_eq!(state.entries.len(), 3);
+        assert_eq!(
+            plain_lines(&state),
+            ["> a question long", "  enough to wrap", "", "The answer.", "", "Saved"]
+        );
+
+        state.scroll_up(5);
+        state.push_reply("Another answer.");
+        assert_eq!(state.scroll_back, 0, "a new entry scrolls back down");
+
+        state.input = "  next  ".to_string();
+        assert_eq!(state.take_input().as_deref(), Some("next"));
+        state.input = "   ".to_string();
+        assert_eq!(state.take_input(), None);
+    }
+
+    #[test]
+    fn conversation_is_drawn_above_the_input() {
+        let mut state = TuiState::new(36);
+        state.push_user("hello");
+        state.push_reply("Hi there!");
+        state.input = "typing".to_string();
+
+        let mut terminal = Terminal::new(TestBackend::new(40, 10)).unwrap();
+        terminal.draw(|frame| draw(frame, &mut state)).unwrap();
+        let screen: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content
+            .chunks(40)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        let position = |text: &str| screen.iter().position(|row| row.contains(text));
+        assert!(position("> hello") < position("Hi there!"), "{:#?}", screen);
+        assert!(position("Hi there!") < position("typing"), "{:#?}", screen);
+    }
+}